@@ -0,0 +1,6 @@
+use keg::run::bench;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    bench::run()
+}
@@ -0,0 +1,6 @@
+use keg::run::cleanup;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    cleanup::run()
+}
@@ -0,0 +1,6 @@
+use keg::run::pause;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    pause::run(true)
+}
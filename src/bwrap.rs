@@ -1,8 +1,9 @@
+use crate::log;
 use std::ffi::OsStr;
 use std::io;
 use std::process::{Child, Command};
 
-pub fn bwrap<I, S>(args: I, env_clear: bool) -> io::Result<Child>
+pub fn bwrap_command<I, S>(args: I, env_clear: bool) -> Command
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
@@ -13,5 +14,14 @@ where
     if env_clear {
         command.env_clear();
     }
-    command.spawn()
+    log::command(&command);
+    command
+}
+
+pub fn bwrap<I, S>(args: I, env_clear: bool) -> io::Result<Child>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    bwrap_command(args, env_clear).spawn()
 }
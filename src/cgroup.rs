@@ -1,10 +1,14 @@
 /// cgroup v2 support.
-use crate::{msg_ret, msg_retf, ok_or, some_or, true_or};
-use libc::{c_char, c_void, mount, umount, MS_SILENT};
+use crate::{msg_ret, msg_retf, ok_or, some_or, true_or, verbose};
+use libc::{c_char, c_void, kill, mount, pid_t, umount, MS_SILENT, SIGKILL};
+use std::env;
 use std::ffi::{CString, OsStr, OsString};
-use std::fs::{create_dir, read, read_link, remove_dir, write};
+use std::fs::{create_dir, read, read_dir, read_link, remove_dir, write};
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
 
 #[must_use]
 pub fn cgroup_init(stage0: bool) -> bool {
@@ -59,6 +63,11 @@ fn umount_cgroup<P: AsRef<Path>>(path: P) -> bool {
 
 /// proc may be "0" to refer to self.
 fn move_one(proc: &[u8], to: &Path) -> bool {
+    verbose!(
+        "Moving process {} to cgroup {}",
+        String::from_utf8_lossy(proc),
+        to.display()
+    );
     ok_or!(
         write(to.join("cgroup.procs"), proc),
         msg_retf!("Failed to move cgroup process")
@@ -129,6 +138,40 @@ fn move_all_matching_ns(from: &Path, to: &Path, ns: &[u8]) -> bool {
     }
 }
 
+/// Disables the controllers enabled in `cgroup.subtree_control`, so processes can be moved back
+/// to `cgroup`.
+fn remove_subtree_control(cgroup: &Path) -> bool {
+    if cgroup == cgroup_mount_root() {
+        // The root cgroup may have both processes and controllers.
+        return true;
+    }
+    let mut controllers = ok_or!(
+        read(cgroup.join("cgroup.subtree_control")),
+        msg_retf!("Cannot read cgroup.subtree_control")
+    );
+    if controllers.last() == Some(&b'\n') {
+        controllers.pop();
+    }
+    if !controllers.is_empty() {
+        let controllers = controllers.split(|c| c == &b' ');
+
+        let mut subtree_control = Vec::new();
+        for controller in controllers {
+            if subtree_control.is_empty() {
+                subtree_control.extend_from_slice(&b"-"[..]);
+            } else {
+                subtree_control.extend_from_slice(&b" -"[..]);
+            }
+            subtree_control.extend_from_slice(controller);
+        }
+        ok_or!(
+            write(cgroup.join("cgroup.subtree_control"), subtree_control),
+            msg_retf!("Cannot write to cgroup.subtree_control")
+        );
+    }
+    true
+}
+
 fn add_subtree_control(cgroup: &Path) -> bool {
     let mut controllers = ok_or!(
         read(cgroup.join("cgroup.controllers")),
@@ -157,10 +200,24 @@ fn add_subtree_control(cgroup: &Path) -> bool {
     true
 }
 
+fn cgroup_mount_root() -> &'static Path {
+    let cgroup_root = Path::new("/sys/fs/cgroup/unified");
+    if cgroup_root.exists() {
+        cgroup_root
+    } else {
+        Path::new("/sys/fs/cgroup")
+    }
+}
+
 fn get_cgroup_root_stage0() -> Option<PathBuf> {
+    get_cgroup_of("self")
+}
+
+/// proc may be "self".
+fn get_cgroup_of(proc: &str) -> Option<PathBuf> {
     let mut entries = ok_or!(
-        read("/proc/self/cgroup"),
-        msg_ret!("Cannot read /proc/self/cgroup")
+        read(format!("/proc/{proc}/cgroup")),
+        msg_ret!("Cannot read /proc/{}/cgroup", proc)
     );
     if entries.last() == Some(&b'\n') {
         entries.pop();
@@ -172,15 +229,120 @@ fn get_cgroup_root_stage0() -> Option<PathBuf> {
                 cgroup.strip_prefix("/"),
                 msg_ret!("cgroup path is not in the current namespace")
             );
+            return Some(cgroup_mount_root().join(cgroup));
+        }
+    }
+    msg_ret!("Only cgroup v2 is supported");
+}
 
-            let mut cgroup_root = Path::new("/sys/fs/cgroup/unified");
-            if !cgroup_root.exists() {
-                cgroup_root = Path::new("/sys/fs/cgroup");
+/// Returns whether `cgroup` or one of its descendants has a process.
+fn is_populated(cgroup: &Path) -> Option<bool> {
+    let events = ok_or!(
+        read(cgroup.join("cgroup.events")),
+        msg_ret!("Cannot read cgroup.events of {}", cgroup.display())
+    );
+    Some(events.split(|c| c == &b'\n').any(|x| x == b"populated 1"))
+}
+
+/// Waits up to a second for the processes of `cgroup` to exit.
+fn wait_unpopulated(cgroup: &Path) -> bool {
+    for _ in 0..100 {
+        if !some_or!(is_populated(cgroup), return false) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+/// Removes `cgroup` and its descendants, which must not have any process.
+fn remove_cgroup(cgroup: &Path) -> bool {
+    let entries = ok_or!(
+        read_dir(cgroup),
+        msg_retf!("Cannot read cgroup {}", cgroup.display())
+    );
+    for entry in entries {
+        let entry = ok_or!(entry, msg_retf!("Cannot read cgroup {}", cgroup.display()));
+        if ok_or!(entry.file_type(), return false).is_dir() {
+            true_or!(remove_cgroup(&entry.path()), return false);
+        }
+    }
+    verbose!("Removing cgroup {}", cgroup.display());
+    ok_or!(
+        remove_dir(cgroup),
+        msg_retf!("Cannot remove cgroup {}", cgroup.display())
+    );
+    true
+}
+
+/// Returns whether `name` is the name of a cgroup created by keg.
+fn is_keg_cgroup(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"unit.keg_") || name.as_bytes().starts_with(b"unit.container_")
+}
+
+/// Returns whether `name` is `unit.keg_<PID>` of a running process, which may be about to move to
+/// it.
+fn is_running_keg_cgroup(name: &OsStr) -> bool {
+    let pid = some_or!(name.as_bytes().strip_prefix(b"unit.keg_"), return false);
+    Path::new("/proc").join(OsStr::from_bytes(pid)).exists()
+}
+
+/// Removes `cgroup` if it was left over by keg, e.g. after keg was killed, and does not have any
+/// process. A leftover `unit.container_other` with processes is kept, and reused.
+fn reclaim_leftover(cgroup: &Path) -> bool {
+    if !cgroup.exists() {
+        return true;
+    }
+    if !some_or!(is_populated(cgroup), return false) {
+        verbose!("Reclaiming leftover cgroup {}", cgroup.display());
+        return remove_cgroup(cgroup);
+    }
+    if cgroup.ends_with("unit.container_other") {
+        return true;
+    }
+    msg_retf!(
+        "Cannot create cgroup {}: it is in use, e.g. by another keg running in the same cgroup.\n\
+         Run keg-cleanup to remove cgroups left over by keg.",
+        cgroup.display()
+    );
+}
+
+/// Returns whether a systemd user session can start a scope for keg.
+pub fn has_systemd_user_session() -> bool {
+    Path::new("/run/systemd/system").exists()
+        && env::var_os("XDG_RUNTIME_DIR")
+            .is_some_and(|dir| Path::new(&dir).join("systemd/private").exists())
+}
+
+/// Moves this process to a new cgroup under its current one, like a systemd scope. The current
+/// cgroup must be writable, e.g. delegated to this user.
+#[must_use]
+pub fn enter_new_cgroup() -> bool {
+    let cgroup = some_or!(get_cgroup_root_stage0(), return false);
+    // Cgroups of a keg that was killed are left over.
+    if let Ok(entries) = read_dir(&cgroup) {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            if name.as_bytes().starts_with(b"unit.keg_") && !is_running_keg_cgroup(&name) {
+                reclaim_leftover(&entry.path());
             }
-            return Some(cgroup_root.join(cgroup));
         }
     }
-    msg_ret!("Only cgroup v2 is supported");
+    let new_cgroup = cgroup.join(format!("unit.keg_{}", process::id()));
+    if let Err(e) = create_dir(&new_cgroup) {
+        msg_retf!(
+            "Cannot create cgroup {}: {}\n\
+             Without a systemd user session, keg needs a writable cgroup v2, e.g. one delegated \
+             to this user. Use --no-cgroup to run without cgroups.",
+            new_cgroup.display(),
+            e
+        );
+    }
+    true_or!(move_one(b"0", &new_cgroup), {
+        drop(remove_dir(&new_cgroup));
+        return false;
+    });
+    true
 }
 
 fn cgroup_init_stage0() -> bool {
@@ -190,10 +352,15 @@ fn cgroup_init_stage0() -> bool {
     let children = cgroup.join("unit.container_children");
     let spawn = cgroup.join("unit.container_spawn");
     let other = cgroup.join("unit.container_other");
+    for leftover in [&parent, &children, &spawn, &other] {
+        true_or!(reclaim_leftover(leftover), return false);
+    }
     ok_or!(create_dir(&parent), msg_retf!("Cannot create cgroup"));
     ok_or!(create_dir(&children), msg_retf!("Cannot create cgroup"));
     ok_or!(create_dir(&spawn), msg_retf!("Cannot create cgroup"));
-    ok_or!(create_dir(&other), msg_retf!("Cannot create cgroup"));
+    if !other.exists() {
+        ok_or!(create_dir(&other), msg_retf!("Cannot create cgroup"));
+    }
     true_or!(move_all(&cgroup, &other), return false);
     true_or!(move_one(&b"0"[..], &spawn), return false);
     true_or!(add_subtree_control(&cgroup), return false);
@@ -201,6 +368,200 @@ fn cgroup_init_stage0() -> bool {
     true
 }
 
+/// Returns the cgroup of the container, after `cgroup_postexec_stage0`.
+pub fn container_cgroup_stage0() -> Option<PathBuf> {
+    let mut cgroup = some_or!(get_cgroup_root_stage0(), return None);
+    true_or!(cgroup.pop(), msg_ret!("cgroup path changed"));
+    Some(cgroup.join("unit.container_children"))
+}
+
+/// Returns the cgroup of the running container whose outermost keg process is `pid`.
+pub fn container_cgroup_of(pid: pid_t) -> Option<PathBuf> {
+    let cgroup = some_or!(get_cgroup_of(&pid.to_string()), return None);
+    true_or!(
+        cgroup.ends_with("unit.container_parent"),
+        msg_ret!("The container does not have a cgroup, e.g. it runs with --no-cgroup")
+    );
+    Some(cgroup.with_file_name("unit.container_children"))
+}
+
+/// Sends SIGKILL to the processes in `cgroup` and its descendants.
+fn kill_procs(cgroup: &Path) -> bool {
+    let procs = ok_or!(
+        read(cgroup.join("cgroup.procs")),
+        msg_retf!("Cannot read cgroup.procs")
+    );
+    for proc in procs.split(|c| c == &b'\n') {
+        let pid: pid_t = some_or!(
+            std::str::from_utf8(proc).ok().and_then(|x| x.parse().ok()),
+            continue
+        );
+        unsafe { kill(pid, SIGKILL) };
+    }
+    let entries = ok_or!(
+        read_dir(cgroup),
+        msg_retf!("Cannot read cgroup {}", cgroup.display())
+    );
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|x| x.is_dir()) {
+            true_or!(kill_procs(&entry.path()), return false);
+        }
+    }
+    true
+}
+
+/// Kills all processes in `cgroup` and its descendants, and waits up to a second for them to exit.
+pub fn kill_cgroup(cgroup: &Path) -> bool {
+    if !some_or!(is_populated(cgroup), return false) {
+        return true;
+    }
+    verbose!("Killing the processes of cgroup {}", cgroup.display());
+    let kill_file = cgroup.join("cgroup.kill");
+    if kill_file.exists() {
+        ok_or!(
+            write(kill_file, b"1"),
+            msg_retf!("Cannot write to cgroup.kill of {}", cgroup.display())
+        );
+    } else {
+        // cgroup.kill requires Linux 5.14. Killed processes may have forked in the meantime.
+        for _ in 0..10 {
+            true_or!(kill_procs(cgroup), return false);
+            if !some_or!(is_populated(cgroup), return false) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    true_or!(
+        wait_unpopulated(cgroup),
+        msg_retf!("Processes of cgroup {} are still running", cgroup.display())
+    );
+    true
+}
+
+/// Freezes or thaws all processes in `cgroup`, and waits up to 10 seconds until they are.
+#[must_use]
+pub fn freeze(cgroup: &Path, frozen: bool) -> bool {
+    let (value, expected) = if frozen {
+        (&b"1"[..], &b"frozen 1"[..])
+    } else {
+        (&b"0"[..], &b"frozen 0"[..])
+    };
+    ok_or!(
+        write(cgroup.join("cgroup.freeze"), value),
+        msg_retf!("Cannot write to cgroup.freeze of {}", cgroup.display())
+    );
+    for _ in 0..1000 {
+        let events = ok_or!(
+            read(cgroup.join("cgroup.events")),
+            msg_retf!("Cannot read cgroup.events of {}", cgroup.display())
+        );
+        if events.split(|c| c == &b'\n').any(|x| x == expected) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    msg_retf!(
+        "Timed out waiting for cgroup.freeze of {}",
+        cgroup.display()
+    );
+}
+
+/// Removes the cgroups created by `cgroup_init_stage0` after the container exited, and moves the
+/// processes of `unit.container_other` back. A cgroup created by `enter_new_cgroup` is removed as
+/// well. With `keep_running`, the cgroups are kept if processes of the container are still
+/// running, and left to keg-cleanup.
+pub fn cgroup_cleanup_stage0(keep_running: bool) -> bool {
+    let mut cgroup = some_or!(get_cgroup_root_stage0(), return false);
+    true_or!(cgroup.pop(), msg_retf!("cgroup path changed"));
+
+    let parent = cgroup.join("unit.container_parent");
+    let children = cgroup.join("unit.container_children");
+    let spawn = cgroup.join("unit.container_spawn");
+    let other = cgroup.join("unit.container_other");
+    true_or!(move_one(&b"0"[..], &parent), return false);
+    if some_or!(is_populated(&children), return false) {
+        true_or!(
+            keep_running,
+            msg_retf!("Cannot remove cgroup {}: it is in use", children.display())
+        );
+        verbose!("Keeping cgroup {} for keg-cleanup", children.display());
+        return true;
+    }
+    true_or!(remove_cgroup(&children), return false);
+    true_or!(remove_cgroup(&spawn), return false);
+    true_or!(remove_subtree_control(&cgroup), return false);
+    true_or!(move_all(&other, &cgroup), return false);
+    true_or!(remove_cgroup(&other), return false);
+    true_or!(move_one(&b"0"[..], &cgroup), return false);
+    true_or!(remove_cgroup(&parent), return false);
+
+    if cgroup.ends_with(format!("unit.keg_{}", process::id())) {
+        let mut outer = cgroup.clone();
+        true_or!(outer.pop(), msg_retf!("cgroup path changed"));
+        true_or!(move_one(&b"0"[..], &outer), return false);
+        true_or!(remove_cgroup(&cgroup), return false);
+    }
+    true
+}
+
+/// Removes the cgroups left over by keg in the current cgroup, e.g. after keg was killed, and moves
+/// the processes of a leftover `unit.container_other` back. Cgroups in use are kept. Prints what is
+/// removed.
+pub fn cleanup_cgroups(dry_run: bool) -> bool {
+    let mut cgroup = some_or!(get_cgroup_root_stage0(), return false);
+    // keg moves the processes of its cgroup, e.g. a shell with --no-new-scope, to
+    // unit.container_other.
+    while cgroup.ends_with("unit.container_other") {
+        cgroup.pop();
+    }
+
+    let entries = ok_or!(
+        read_dir(&cgroup),
+        msg_retf!("Cannot read cgroup {}", cgroup.display())
+    );
+    let mut other = None;
+    let mut in_use = false;
+    for entry in entries {
+        let entry = ok_or!(entry, msg_retf!("Cannot read cgroup {}", cgroup.display()));
+        let name = entry.file_name();
+        if !is_keg_cgroup(&name) || !ok_or!(entry.file_type(), return false).is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        if name == "unit.container_other" {
+            other = Some(path);
+            continue;
+        }
+        if is_running_keg_cgroup(&name) || some_or!(is_populated(&path), return false) {
+            println!("In use: {}", path.display());
+            in_use |= name.as_bytes().starts_with(b"unit.container_");
+            continue;
+        }
+        if dry_run {
+            println!("Would remove {}", path.display());
+        } else {
+            true_or!(remove_cgroup(&path), return false);
+            println!("Removed {}", path.display());
+        }
+    }
+
+    let other = some_or!(other, return true);
+    if in_use {
+        println!("In use: {}", other.display());
+    } else if dry_run {
+        println!("Would remove {}", other.display());
+    } else {
+        if some_or!(is_populated(&other), return false) {
+            true_or!(remove_subtree_control(&cgroup), return false);
+            true_or!(move_all(&other, &cgroup), return false);
+        }
+        true_or!(remove_cgroup(&other), return false);
+        println!("Removed {}", other.display());
+    }
+    true
+}
+
 fn cgroup_preexec_stage0() -> bool {
     let mut cgroup = PathBuf::from(some_or!(get_cgroup_root_stage0(), return false));
     true_or!(cgroup.pop(), msg_retf!("cgroup path changed"));
@@ -1,15 +1,32 @@
 mod exec;
 mod isolation;
 mod mounting;
+mod nft;
+mod plan;
 mod utils;
 
+use utils::{filesystem_entries, read_entries};
+
+#[cfg(any(test, feature = "fuzz"))]
+pub(crate) use exec::is_valid_env_var;
+pub use nft::check_nft_rules;
+
+use crate::ipc;
 use crate::keyring;
+use crate::netns;
 use crate::seccomp;
-use crate::{msg_ret, true_or};
-use libc::{gid_t, pid_t, uid_t};
+use crate::{msg_ret, msg_retf, ok_or, true_or, verbose};
+use libc::{c_int, gid_t, pid_t, uid_t};
 use serde::{Deserialize, Serialize};
+use std::env;
 use std::ffi::OsString;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
 use std::process::ExitStatus;
+use std::time::Instant;
+
+pub const DEFAULT_HOSTNAME: &str = "container";
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct SetEnv {
@@ -28,6 +45,56 @@ pub struct Mount {
     pub path: OsString,
 }
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Host {
+    pub name: OsString,
+    pub ip: IpAddr,
+}
+
+/// Options of slirp4netns, used by both keg and podman.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Slirp {
+    pub cidr: Option<OsString>,
+    pub mtu: Option<u32>,
+    pub disable_ipv6: bool,
+    /// Name server used by the DNS forwarder of slirp4netns.
+    pub dns_upstream: Option<IpAddr>,
+}
+
+/// Offsets of the monotonic and boot time clocks in the time namespace of the container, in
+/// nanoseconds.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct TimeOffsets {
+    pub monotonic: i64,
+    pub boottime: i64,
+}
+
+/// Dummy `/proc/loadavg`, `/proc/stat` and `/proc/uptime`, generated by the mounting stage.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct DummyProc {
+    /// Number of CPUs in `/proc/stat` and `/proc/cpuinfo`.
+    pub cpus: u32,
+    /// Contents given with `--dummy-proc-file`, as (file name, contents).
+    pub files: Vec<(String, Vec<u8>)>,
+    /// The generated `/etc/machine-id` and `/var/lib/dbus/machine-id` with `--private-identity`,
+    /// which also replaces `/proc/cpuinfo`.
+    pub machine_id: Option<String>,
+    /// Whether the files are bound over `/proc` and `/etc` of the final container, instead of to
+    /// `/container_dummy_<NAME>` for podman.
+    pub in_place: bool,
+}
+
+impl Default for DummyProc {
+    fn default() -> Self {
+        Self {
+            cpus: 1,
+            files: Vec::new(),
+            machine_id: None,
+            in_place: false,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum Options {
     SetEnv(SetEnv),
@@ -44,13 +111,48 @@ pub enum Options {
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct Container {
     pub share_net: bool,
+    /// Outermost process of another container whose final network namespace is joined.
+    pub join_net: Option<pid_t>,
     pub share_time: bool,
+    /// Written by the stage that unshares the time namespace, see `--time-offset`.
+    pub time_offsets: Option<TimeOffsets>,
+    /// Whether the container is reproducible, see `--hermetic`.
+    pub hermetic: bool,
+    /// Whether identifying files of the host are hidden, see `--private-identity`.
+    pub private_identity: bool,
+    /// Whether cgroups are left alone, see `--no-cgroup`.
+    pub no_cgroup: bool,
+    /// Whether to print the resource usage of the container when it exits, see `--stats`.
+    pub stats: bool,
+    /// File the resource usage of the container is written to as JSON when it exits.
+    pub stats_file: Option<OsString>,
+    /// Whether to freeze the container while keg is suspended, see `--freeze-on-suspend`.
+    pub freeze_on_suspend: bool,
+    /// Whether processes of the container are not killed when keg exits, see `--keep-running`.
+    pub keep_running: bool,
+    /// Seconds the container has to exit after keg receives SIGINT, SIGTERM or SIGHUP, see
+    /// `--stop-timeout`.
+    pub stop_timeout: u32,
     pub keep_env: bool,
     pub base_image: Option<OsString>,
+    pub hostname: Option<OsString>,
+    /// Entries of the generated `/etc/hosts` in the final container.
+    pub hosts: Vec<Host>,
+    /// Name servers of the generated `/etc/resolv.conf` in the final container.
+    pub dns: Vec<IpAddr>,
     pub net_nft_rules: Vec<u8>,
+    /// Inherited file descriptor the network traffic summary is written to by the nft stage.
+    pub net_log_fd: Option<c_int>,
+    pub slirp: Slirp,
+    /// `log::VERBOSE`, `log::DEBUG`, or 0 to not log.
+    pub log_level: u8,
+    /// Inherited file descriptor of the log file, instead of stderr.
+    pub log_fd: Option<c_int>,
     pub unshare_user: Option<(uid_t, gid_t)>,
     pub options: Vec<Options>,
-    pub create_dummy_files: bool,
+    pub dummy_proc: Option<DummyProc>,
+    /// Whether the exec stage reaps the orphaned processes of the container, see `--init`.
+    pub init: bool,
     pub command_before_unshare_user: Vec<OsString>,
     pub command: Vec<OsString>,
 }
@@ -59,13 +161,31 @@ impl Default for Container {
     fn default() -> Self {
         Self {
             share_net: false,
+            join_net: None,
             share_time: false,
+            time_offsets: None,
+            hermetic: false,
+            private_identity: false,
+            no_cgroup: false,
+            stats: false,
+            stats_file: None,
+            freeze_on_suspend: false,
+            keep_running: false,
+            stop_timeout: 10,
             keep_env: false,
             base_image: None,
+            hostname: None,
+            hosts: Vec::new(),
+            dns: Vec::new(),
             net_nft_rules: Vec::new(),
+            net_log_fd: None,
+            slirp: Slirp::default(),
+            log_level: 0,
+            log_fd: None,
             unshare_user: None,
             options: Vec::new(),
-            create_dummy_files: false,
+            dummy_proc: None,
+            init: false,
             command_before_unshare_user: Vec::new(),
             command: Vec::new(),
         }
@@ -79,15 +199,39 @@ pub enum Stage {
     Exec,
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct ContainerRunner {
     pub stage: Stage,
     pub container: Container,
     pub env: Vec<(OsString, OsString)>,
 }
 
+/// Omits the values of the environment variables and the nft rules, which can hold secrets and
+/// would otherwise end up in the log of `--debug`.
+impl fmt::Debug for ContainerRunner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let set_env: Vec<_> = self
+            .container
+            .options
+            .iter()
+            .filter_map(|option| match option {
+                Options::SetEnv(SetEnv { key, .. }) => Some(key),
+                _ => None,
+            })
+            .collect();
+        f.debug_struct("ContainerRunner")
+            .field("stage", &self.stage)
+            .field("env", &self.env.iter().map(|(k, _)| k).collect::<Vec<_>>())
+            .field("set_env", &set_env)
+            .field("options", &self.container.options.len())
+            .field("net_nft_rules", &self.container.net_nft_rules.len())
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct ContainerRunnerResponse {
+    /// The pid of the stage, which the outer stage replaces with the pid in its pid namespace.
     pub pid: pid_t,
 }
 
@@ -100,23 +244,74 @@ pub fn run_container(
     // Stages:
     // Isolation:
     // stage 0: container: cap_setfcap, cap_sys_admin, share net, bind /dev/net/tun.
-    // stage 1: unshare time. container: cap_setfcap, cap_sys_admin, share pid, bind /dev/net/tun. run slirp.
-    // stage 2: unshare time. container: cap_setfcap, cap_sys_admin, share net, bind /dev/net/tun.
-    // stage 3: unshare time. container: cap_setfcap, cap_sys_admin, cap_net_admin, share pid, bind /dev/net/tun. run slirp.
-    // stage 4: unshare time. bwrap(nft, cap_net_admin). container: cap_setfcap, cap_sys_admin, share net, bind /dev/net/tun.
-    // stage 5: unshare time. container: cap_setfcap, cap_sys_admin, share pid. run slirp.
-    // stage 6: unshare time. container: cap_setfcap, cap_sys_admin, share net.
+    // stage 1: container: cap_setfcap, cap_sys_admin, share pid, bind /dev/net/tun. run slirp.
+    // stage 2: container: cap_setfcap, cap_sys_admin, share net, bind /dev/net/tun.
+    // stage 3: container: cap_setfcap, cap_sys_admin, cap_net_admin, share pid, bind /dev/net/tun. run slirp.
+    // stage 4: bwrap(nft, cap_net_admin). write net log after exit. container: cap_setfcap, cap_sys_admin, share net, bind /dev/net/tun.
+    // stage 5: container: cap_setfcap, cap_sys_admin, share pid. run slirp.
+    // stage 6: container: cap_setfcap, cap_sys_admin, share net.
     // Mounting.
-    // Exec: Set env. Exec.
+    // Exec: Set env. Run the command and wait for it.
+    // With `join_net`, no stage unshares net and slirp is not run.
+    // The first stage after stage 0 unshares time; later stages inherit the namespace.
+    // Without a network to reach, stages 1 to 5 are skipped, see `plan::next_stage`.
 
-    match stage {
+    verbose!("Entered");
+    let start = Instant::now();
+    let result = match stage {
         Stage::Isolation(stage) => isolation::run_container(stage, container, env, wait),
         Stage::Mounting => mounting::run_container(container, env, wait),
         Stage::Exec => exec::run_container(container, env, wait),
+    };
+    match &result {
+        Some(status) => verbose!(
+            "Exited with {} after {:.6}s",
+            status,
+            start.elapsed().as_secs_f64()
+        ),
+        None => verbose!("Failed after {:.6}s", start.elapsed().as_secs_f64()),
+    }
+    result
+}
+
+/// Prints what `start_container` would do, as JSON if `json`, without starting anything.
+#[must_use]
+pub fn print_plan(container: &Container, env: &[(OsString, OsString)], json: bool) -> bool {
+    let (entries, etc, dbus) = match &container.base_image {
+        Some(base_image) => (
+            read_entries(base_image),
+            read_entries(Path::new(base_image).join("etc")),
+            read_entries(Path::new(base_image).join("var/lib/dbus")),
+        ),
+        None => (
+            filesystem_entries(),
+            read_entries("/etc"),
+            read_entries("/var/lib/dbus"),
+        ),
+    };
+    let entries = ok_or!(entries, msg_retf!("Failed to read the base image"));
+    let keg_bin = ok_or!(env::current_exe(), msg_retf!("Failed getting current exe"));
+    let (etc, dbus) = (etc.unwrap_or_default(), dbus.unwrap_or_default());
+    let plan = match plan::plan(container, env, &entries, &etc, &dbus, &keg_bin) {
+        Ok(plan) => plan,
+        Err(e) => msg_retf!("{}", e),
+    };
+    if json {
+        print!("{}", plan::to_json(&plan));
+    } else {
+        print!("{}", plan::to_text(&plan));
     }
+    true
 }
 
 pub fn start_container(container: &Container, env: &[(OsString, OsString)]) -> Option<ExitStatus> {
+    ipc::set_stage(Stage::Isolation(0));
+    if let Some(pid) = container.join_net {
+        true_or!(
+            netns::join(pid),
+            msg_ret!("Failed to join network namespace")
+        );
+    }
     true_or!(seccomp::apply(), msg_ret!("Failed to apply seccomp rules"));
     true_or!(
         keyring::apply(),
@@ -1,17 +1,29 @@
 use super::Container;
 use crate::cgroup::{cgroup_init_stage_exec, mount_cgroup};
-use crate::{msg_ret, ok_or, true_or};
-use core::ptr;
-use libc::{c_char, execv, unshare, CLONE_NEWUSER};
+use crate::init;
+use crate::ipc;
+use crate::log;
+use crate::status::{exit_status, CANNOT_EXECUTE, NOT_FOUND};
+use crate::{msg_and, msg_ret, ok_or, true_or, verbose};
+use libc::{unshare, CLONE_NEWUSER, ENOENT, ENOTDIR};
 use std::env;
-use std::ffi::{CString, OsString};
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
 use std::process::{Command, ExitStatus};
 
+/// Returns whether `key=value` can be set in the environment, and is read back as `key` and
+/// `value`.
+pub(crate) fn is_valid_env_var(key: &OsStr, value: &OsStr) -> bool {
+    !key.is_empty()
+        && !key.as_bytes().contains(&b'=')
+        && !key.as_bytes().contains(&b'\0')
+        && !value.as_bytes().contains(&b'\0')
+}
+
 pub fn run_container(
     container: &Container,
     env: &[(OsString, OsString)],
@@ -19,73 +31,30 @@ pub fn run_container(
 ) -> Option<ExitStatus> {
     assert!(wait);
 
-    if container.create_dummy_files {
-        ok_or!(
-            fs::write("/container_dummy_loadavg", b"1.00 1.00 1.00 1/100 1\n"),
-            msg_ret!("Failed to write dummy loadavg")
-        );
-        ok_or!(
-            fs::write(
-                "/container_dummy_stat",
-                b"cpu  0 0 0 0 0 0 0 0 0 0
-cpu0 0 0 0 0 0 0 0 0 0 0
-intr 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0
-ctxt 0
-btime 100
-processes 100
-procs_running 1
-procs_blocked 0
-softirq 0 0 0 0 0 0 0 0 0 0 0
-"
-            ),
-            msg_ret!("Failed to write dummy stat")
-        );
-        ok_or!(
-            fs::write("/container_dummy_uptime", b"100.00 100.00\n"),
-            msg_ret!("Failed to write dummy uptime")
-        );
-        ok_or!(
-            fs::set_permissions(
-                "/container_dummy_loadavg",
-                fs::Permissions::from_mode(0o444)
-            ),
-            msg_ret!("Failed to chmod dummy loadavg")
-        );
-        ok_or!(
-            fs::set_permissions("/container_dummy_stat", fs::Permissions::from_mode(0o444)),
-            msg_ret!("Failed to chmod dummy stat")
+    if !container.no_cgroup {
+        true_or!(
+            mount_cgroup("/sys/fs/cgroup"),
+            msg_ret!("Failed to mount cgroup")
         );
-        ok_or!(
-            fs::set_permissions("/container_dummy_uptime", fs::Permissions::from_mode(0o444)),
-            msg_ret!("Failed to chmod dummy uptime")
+        true_or!(
+            cgroup_init_stage_exec(),
+            msg_ret!("Failed to initialize cgroup")
         );
     }
 
-    true_or!(
-        mount_cgroup("/sys/fs/cgroup"),
-        msg_ret!("Failed to mount cgroup")
-    );
-    true_or!(
-        cgroup_init_stage_exec(),
-        msg_ret!("Failed to initialize cgroup")
-    );
-
     for (k, v) in env {
-        if k.is_empty()
-            || k.as_bytes().contains(&b'=')
-            || k.as_bytes().contains(&b'\0')
-            || v.as_bytes().contains(&b'\0')
-        {
-            msg_ret!("Invalid environment variable");
-        }
+        true_or!(
+            is_valid_env_var(k, v),
+            msg_ret!("Invalid environment variable")
+        );
         env::set_var(k, v);
     }
 
     if !container.command_before_unshare_user.is_empty() {
-        let mut child = match Command::new(&container.command_before_unshare_user[0])
-            .args(&container.command_before_unshare_user[1..])
-            .spawn()
-        {
+        let mut command = Command::new(&container.command_before_unshare_user[0]);
+        command.args(&container.command_before_unshare_user[1..]);
+        log::command(&command);
+        let mut child = match command.spawn() {
             Err(e) => msg_ret!(
                 "Failed to run command before unshare: Running `{}`: {}",
                 Path::new(&container.command_before_unshare_user[0]).display(),
@@ -125,26 +94,44 @@ softirq 0 0 0 0 0 0 0 0 0 0 0
         !container.command.is_empty(),
         msg_ret!("Command cannot be empty")
     );
-    let mut argv_c = Vec::new();
-    for arg in &container.command {
-        argv_c.push(ok_or!(
-            CString::new(arg.as_bytes().to_owned()),
-            msg_ret!("Bad command")
-        ));
-    }
-    let mut argv_ptr = Vec::new();
-    for arg in &argv_c {
-        argv_ptr.push(arg.as_bytes().as_ptr() as *const c_char);
-    }
-    argv_ptr.push(ptr::null());
-    unsafe {
-        execv(argv_ptr[0], argv_ptr.as_ptr());
+    true_or!(
+        container
+            .command
+            .iter()
+            .all(|arg| !arg.as_bytes().contains(&0)),
+        msg_ret!("Bad command")
+    );
+    verbose!(
+        "Executing {}",
+        log::command_line(
+            &container.command[0],
+            container.command[1..].iter().map(|x| &x[..])
+        )
+    );
+    log::close_on_exec();
+    let mut command = Command::new(&container.command[0]);
+    command.args(&container.command[1..]);
+    let status = match init::run(&mut command, container.init) {
+        Ok(status) => status?,
+        Err(error) => return command_failed(&container.command[0], error),
+    };
+    if let Some(signal) = status.signal() {
+        // Stage 0 only sees the exit code of bwrap, which is the same for `exit 130`.
+        ipc::report_signal(signal);
     }
-    // execv failed.
-    let error = io::Error::last_os_error();
-    msg_ret!(
+    Some(status)
+}
+
+/// Reports that `program` cannot be executed, and returns the exit status for it.
+fn command_failed(program: &OsStr, error: io::Error) -> Option<ExitStatus> {
+    let code = match error.raw_os_error() {
+        Some(ENOENT) | Some(ENOTDIR) => NOT_FOUND,
+        _ => CANNOT_EXECUTE,
+    };
+    msg_and!(
         "execv failed: Running `{}`: {}",
-        Path::new(&container.command[0]).display(),
-        error
-    );
+        Path::new(program).display(),
+        error;
+        Some(exit_status(code))
+    )
 }
@@ -1,39 +1,94 @@
-use super::utils::{ro_bind_subentries_keep_symlinks, CLONE_NEWTIME};
-use super::{Bind, Container, ContainerRunner, ContainerRunnerResponse, Options, SetEnv, Stage};
+use super::nft::{run_nft, write_net_log, ConnectionLog, NET_LOG_RULES};
+use super::plan::{
+    isolation_args, next_container, next_stage, process_env, runs_slirp, slirp_args,
+};
+use super::utils::{filesystem_entries, read_entries, unshare_time};
+use super::{Container, ContainerRunner, ContainerRunnerResponse, Stage};
 use crate::bwrap::bwrap;
-use crate::cgroup::{cgroup_init, cgroup_postexec, cgroup_preexec};
-use crate::filesystem;
+use crate::cgroup::{
+    cgroup_cleanup_stage0, cgroup_init, cgroup_postexec, cgroup_preexec, container_cgroup_stage0,
+    kill_cgroup,
+};
+use crate::etc_files;
+use crate::ipc;
 use crate::slirp::slirp;
 use crate::socket_pair::{set_cloexec, socket_pair};
-use crate::{msg_and, msg_ret, ok_or, some_or, true_or};
-use bincode;
-use libc::{close, unshare};
+use crate::stats::{self, write_stats_file};
+use crate::suspend::freeze_on_suspend;
+use crate::teardown::{self, block_signals, Stop};
+use crate::{debug, msg_and, msg_ret, ok_or, some_or, some_or_ret, true_or, verbose};
+use libc::close;
 use std::borrow::Cow;
-use std::collections::HashMap;
 use std::env;
 use std::ffi::OsString;
-use std::fs;
-use std::io::{self, Read, Write};
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::IpAddr;
+use std::os::unix::io::FromRawFd;
 use std::os::unix::process::ExitStatusExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
+use std::time::{Duration, Instant};
 
-fn run_slirp(_container: &Container, response: &ContainerRunnerResponse) -> bool {
+/// Writes the `resolv.conf` for slirp4netns. `/etc/resolv.conf` may be a symlink to a file that
+/// does not exist in this container, e.g. `/run/systemd/resolve/stub-resolv.conf`, in which case
+/// the target is created so it can be bind mounted over.
+fn write_slirp_resolv_conf(dns_upstream: &IpAddr) -> bool {
+    ok_or!(
+        fs::write(
+            "/container_slirp_resolv_conf",
+            etc_files::resolv_conf(&[*dns_upstream])
+        ),
+        msg_and!("Failed to write resolv.conf for slirp4netns"; return false)
+    );
+    if let Ok(target) = fs::read_link("/etc/resolv.conf") {
+        let target = Path::new("/etc").join(target);
+        if !target.exists() {
+            if let Some(parent) = target.parent() {
+                ok_or!(
+                    fs::create_dir_all(parent),
+                    msg_and!("Failed to create {}", parent.display(); return false)
+                );
+            }
+            ok_or!(
+                fs::write(&target, b""),
+                msg_and!("Failed to create {}", target.display(); return false)
+            );
+        }
+    }
+    true
+}
+
+/// Reports the resource usage of the container, after it exited.
+fn report_stats(container: &Container, cgroup: &Path, wall_time: Duration) {
+    let stats = stats::read(cgroup, wall_time);
+    if container.stats {
+        eprint!("{}", stats::to_text(&stats));
+    }
+    if let Some(path) = &container.stats_file {
+        write_stats_file(path, &stats);
+    }
+}
+
+fn run_slirp(container: &Container, response: &ContainerRunnerResponse) -> bool {
     let (mut slirp_stream, slirp_sock) = some_or!(
         socket_pair(),
         msg_and!("Cannot create socket pair"; return false)
     );
 
-    let mut args = Vec::<OsString>::new();
-    args.push("--configure".into());
-    args.push("--ready-fd".into());
-    args.push(slirp_sock.to_string().into());
-    args.push("--enable-ipv6".into());
-    args.push("--disable-host-loopback".into());
-    args.push(response.pid.to_string().into());
-    args.push("tap0".into());
+    let args = slirp_args(
+        container,
+        &slirp_sock.to_string(),
+        &response.pid.to_string(),
+    );
+
+    let mut resolv_conf = None;
+    if let Some(dns_upstream) = &container.slirp.dns_upstream {
+        true_or!(write_slirp_resolv_conf(dns_upstream), return false);
+        resolv_conf = Some(Path::new("/container_slirp_resolv_conf"));
+    }
 
-    let result = slirp(args).map(|_| ());
+    let result = slirp(args, resolv_conf).map(|_| ());
     true_or!(unsafe { set_cloexec(slirp_sock) }, return false);
     unsafe { close(slirp_sock) };
 
@@ -48,122 +103,39 @@ fn run_slirp(_container: &Container, response: &ContainerRunnerResponse) -> bool
     true
 }
 
-fn process_env(container: &Container, env: &[(OsString, OsString)]) -> Vec<(OsString, OsString)> {
-    let mut env_map = HashMap::new();
-    env_map.insert(
-        "PATH".into(),
-        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".into(),
-    );
-
-    if container.keep_env {
-        env_map = HashMap::with_capacity(env.len());
-        for (k, v) in env {
-            env_map.insert(k.to_owned(), v.to_owned());
-        }
+pub fn run_container(
+    stage: u8,
+    container: &Container,
+    env: &[(OsString, OsString)],
+    wait: bool,
+) -> Option<ExitStatus> {
+    if !container.no_cgroup {
+        true_or!(cgroup_init(stage == 0), return None);
     }
-    for option in &container.options {
-        match option {
-            Options::SetEnv(SetEnv { key, value }) => {
-                env_map.insert(key.to_owned(), value.to_owned());
+    let start = Instant::now();
+    let exit_status = run_stage(stage, container, env, wait);
+    if stage == 0 && !container.no_cgroup {
+        let wall_time = start.elapsed();
+        if let Some(cgroup) = container_cgroup_stage0() {
+            if !container.keep_running {
+                // Processes that outlived the command, e.g. daemons.
+                kill_cgroup(&cgroup);
             }
-            Options::UnsetEnv(key) => {
-                env_map.remove(key);
+            if exit_status.is_some() && (container.stats || container.stats_file.is_some()) {
+                report_stats(container, &cgroup, wall_time);
             }
-            _ => (),
-        };
-    }
-    env_map.into_iter().collect()
-}
-
-fn cleanup_container(container: &mut Container) {
-    container.keep_env = false;
-    container.base_image = None;
-    container.options.retain_mut(|option| match option {
-        Options::SetEnv(_) | Options::UnsetEnv(_) => false,
-        Options::Bind(Bind { src, dest: _ }) => {
-            *src = "".into();
-            true
-        }
-        Options::DevBind(Bind { src, dest: _ }) => {
-            *src = "".into();
-            true
         }
-        Options::RoBind(Bind { src, dest: _ }) => {
-            *src = "".into();
-            true
-        }
-        Options::Symlink(_) => true,
-        Options::Dir(_) => true,
-    });
-}
-
-fn run_nft(rules: &Path) -> bool {
-    let mut args = Vec::<OsString>::new();
-    args.push("--unshare-ipc".into());
-    args.push("--unshare-pid".into());
-    args.push("--unshare-uts".into());
-    args.push("--unshare-cgroup".into());
-    args.push("--uid".into());
-    args.push("0".into());
-    args.push("--gid".into());
-    args.push("0".into());
-    args.push("--hostname".into());
-    args.push("".into());
-    args.push("--chdir".into());
-    args.push("/".into());
-    args.append(&mut ok_or!(
-        ro_bind_subentries_keep_symlinks("/container_staging_image", "/"),
-        msg_and!("Failed binding staging image"; return false)
-    ));
-    args.push("--ro-bind".into());
-    args.push(rules.to_owned().into());
-    args.push("/container_net_nft_rules".into());
-    args.push("--die-with-parent".into());
-    args.push("--cap-drop".into());
-    args.push("all".into());
-    args.push("--cap-add".into());
-    args.push("cap_net_admin".into());
-    args.push("--".into());
-
-    args.push("/usr/sbin/nft".into());
-    args.push("-f".into());
-    args.push("/container_net_nft_rules".into());
-
-    let exit_status = ok_or!(ok_or!(bwrap(args, true), return false).wait(), return false);
-    true_or!(exit_status.success(), return false);
-    true
-}
-
-pub fn ro_bind_filesystem<D>(dest: D) -> io::Result<Vec<OsString>>
-where
-    D: AsRef<Path>,
-{
-    let dest: &Path = dest.as_ref();
-
-    let mut result = Vec::new();
-    filesystem::iterate(false, |file_name, symlink| match symlink {
-        None => {
-            result.push("--ro-bind".into());
-            result.push(Path::new("/").join(file_name).into());
-            result.push(dest.join(file_name).into());
-        }
-        Some(symlink) => {
-            result.push("--symlink".into());
-            result.push(symlink.into());
-            result.push(dest.join(file_name).into());
-        }
-    })?;
-    Ok(result)
+        cgroup_cleanup_stage0(container.keep_running);
+    }
+    exit_status
 }
 
-pub fn run_container(
+fn run_stage(
     stage: u8,
     container: &Container,
     env: &[(OsString, OsString)],
     wait: bool,
 ) -> Option<ExitStatus> {
-    true_or!(cgroup_init(stage == 0), return None);
-
     let env: Cow<_> = if stage == 0 {
         Cow::Owned(process_env(container, env))
     } else {
@@ -171,7 +143,7 @@ pub fn run_container(
     };
 
     if !container.share_time && stage > 0 {
-        true_or!(unsafe { unshare(CLONE_NEWTIME) } == 0, return None);
+        true_or!(unshare_time(container.time_offsets.as_ref()), return None);
     }
     if stage == 4 {
         // Load nft rules and **make sure** the load succeeds.
@@ -181,121 +153,43 @@ pub fn run_container(
         );
         let result = run_nft(Path::new("/container_net_nft_rules"));
         ok_or!(fs::remove_file("/container_net_nft_rules"), return None);
-        true_or!(result, return None);
-    }
-
-    let mut args = Vec::<OsString>::new();
-    args.push("--unshare-user".into());
-    args.push("--unshare-ipc".into());
-    if stage != 1 && stage != 3 && stage != 5 {
-        args.push("--unshare-pid".into());
-    }
-    if !container.share_net || (stage != 0 && stage != 2 && stage != 4 && stage != 6) {
-        args.push("--unshare-net".into());
-    }
-    args.push("--unshare-uts".into());
-    args.push("--unshare-cgroup".into());
-    args.push("--uid".into());
-    args.push("0".into());
-    args.push("--gid".into());
-    args.push("0".into());
-    args.push("--hostname".into());
-    args.push("container".into());
-    args.push("--chdir".into());
-    args.push("/".into());
-    args.push("--die-with-parent".into());
-    args.push("--cap-drop".into());
-    args.push("all".into());
-    args.push("--cap-add".into());
-    args.push("cap_setfcap".into());
-    args.push("--cap-add".into());
-    args.push("cap_sys_admin".into());
-    if stage == 3 {
-        args.push("--cap-add".into());
-        args.push("cap_net_admin".into());
+        true_or!(result, msg_ret!("Failed to load nft rules"));
+        if let Some(fd) = container.net_log_fd {
+            true_or!(unsafe { set_cloexec(fd) }, return None);
+            ok_or!(
+                fs::write("/container_net_log_rules", NET_LOG_RULES),
+                return None
+            );
+            let result = run_nft(Path::new("/container_net_log_rules"));
+            ok_or!(fs::remove_file("/container_net_log_rules"), return None);
+            true_or!(
+                result,
+                msg_ret!("Failed to load net log rules, which need the nfnetlink_log module")
+            );
+        }
     }
+    let connection_log = if stage == 4 && container.net_log_fd.is_some() {
+        Some(some_or!(
+            ConnectionLog::start(),
+            msg_ret!("Failed to log new connections")
+        ))
+    } else {
+        None
+    };
 
-    if stage == 0 {
-        if let Some(base_image) = &container.base_image {
-            args.append(&mut ok_or!(
-                ro_bind_subentries_keep_symlinks(base_image, "/"),
-                msg_ret!("Failed binding staging image")
-            ));
-            args.append(&mut ok_or!(
-                ro_bind_subentries_keep_symlinks(base_image, "/container_staging_image"),
-                msg_ret!("Failed binding staging image")
-            ));
-        } else {
-            args.append(&mut ok_or!(
-                ro_bind_filesystem("/"),
-                msg_ret!("Failed binding staging image")
-            ));
-            args.append(&mut ok_or!(
-                ro_bind_filesystem("/container_staging_image"),
-                msg_ret!("Failed binding staging image")
-            ));
-        }
+    let (entries, keg_bin) = if stage == 0 {
+        let entries = match &container.base_image {
+            Some(base_image) => read_entries(base_image),
+            None => filesystem_entries(),
+        };
         let current_exe = ok_or!(env::current_exe(), msg_ret!("Failed getting current exe"));
-        args.push("--ro-bind".into());
-        args.push(current_exe.clone().into());
-        args.push("/keg-bin".into());
-        args.push("--ro-bind".into());
-        args.push(current_exe.into());
-        args.push("/container_staging_image/keg-bin".into());
+        (entries, current_exe)
     } else {
-        args.append(&mut ok_or!(
-            ro_bind_subentries_keep_symlinks("/container_staging_image", "/"),
-            msg_ret!("Failed binding staging image")
-        ));
-        args.append(&mut ok_or!(
-            ro_bind_subentries_keep_symlinks(
-                "/container_staging_image",
-                "/container_staging_image"
-            ),
-            msg_ret!("Failed binding staging image")
-        ));
-    }
-
-    args.push("--proc".into());
-    args.push("/proc".into());
-    args.push("--tmpfs".into());
-    args.push("/tmp".into());
-    args.push("--dev".into());
-    args.push("/dev".into());
-    args.push("--mqueue".into());
-    args.push("/dev/mqueue".into());
-    args.push("--dev-bind".into());
-    args.push("/dev/fuse".into());
-    args.push("/dev/fuse".into());
-    args.push("--dev-bind".into());
-    args.push("/dev/net/tun".into());
-    args.push("/dev/net/tun".into());
+        (read_entries("/container_staging_image"), PathBuf::new())
+    };
+    let entries = ok_or!(entries, msg_ret!("Failed binding staging image"));
+    let mut args = isolation_args(stage, container, &entries, &keg_bin);
 
-    let mut bind_index: u64 = 0;
-    for option in &container.options {
-        // Binds
-        let bind = match option {
-            Options::Bind(bind) => Some(bind),
-            Options::DevBind(bind) => Some(bind),
-            Options::RoBind(bind) => Some(bind),
-            _ => None,
-        };
-        match option {
-            Options::Bind(_) => args.push("--bind".into()),
-            Options::DevBind(_) => args.push("--dev-bind".into()),
-            Options::RoBind(_) => args.push("--ro-bind".into()),
-            _ => (),
-        }
-        if let Some(Bind { src, dest: _ }) = bind {
-            if stage == 0 {
-                args.push(src.clone());
-            } else {
-                args.push(("/container_bind_".to_owned() + &bind_index.to_string()).into());
-            }
-            args.push(("/container_bind_".to_owned() + &bind_index.to_string()).into());
-            bind_index += 1;
-        }
-    }
     let (mut stream, sock) = some_or!(socket_pair(), msg_ret!("Cannot create socket pair"));
     // TODO: Close the other socket on error
 
@@ -304,7 +198,11 @@ pub fn run_container(
     args.push("--inner".into());
     args.push(sock.to_string().into());
 
-    true_or!(cgroup_preexec(stage == 0), return None);
+    if !container.no_cgroup {
+        true_or!(cgroup_preexec(stage == 0), return None);
+    }
+    let signals = some_or!(block_signals(), return None);
+    let start = Instant::now();
     let result = bwrap(args, true);
     true_or!(unsafe { set_cloexec(sock) }, return None);
     unsafe { close(sock) };
@@ -317,38 +215,55 @@ pub fn run_container(
         }
     };
 
-    let mut container_clone = container.clone();
-    if stage == 0 {
-        // Remove information we already applied.
-        cleanup_container(&mut container_clone);
-    }
-    if stage == 4 {
-        // nft rules already applied.
-        container_clone.net_nft_rules = Vec::new();
-    }
+    let container_clone = next_container(&Stage::Isolation(stage), container);
     let runner = ContainerRunner {
-        stage: if stage <= 5 {
-            Stage::Isolation(stage + 1)
-        } else {
-            Stage::Mounting
-        },
+        stage: next_stage(&Stage::Isolation(stage), container),
         container: container_clone,
         env: env.into_owned(),
     };
-    ok_or!(
-        stream.write_all(&ok_or!(bincode::serialize(&runner), return None)),
-        return None
+    debug!("Sending {:?}", runner);
+    let response = match ipc::start_stage(&mut stream, &runner) {
+        Ok(response) => some_or_ret!(response),
+        Err(e) => msg_ret!("Failed to start {}: {}", runner.stage, e),
+    };
+    verbose!(
+        "Started {} as pid {} after {:.6}s",
+        runner.stage,
+        response.pid,
+        start.elapsed().as_secs_f64()
     );
-    let response: ContainerRunnerResponse =
-        ok_or!(bincode::deserialize_from(&mut stream), return None);
-    true_or!(cgroup_postexec(stage == 0), return None);
-    if stage == 1 || stage == 3 || stage == 5 {
+    if !container.no_cgroup {
+        true_or!(cgroup_postexec(stage == 0), return None);
+    }
+    if stage == 0 && container.freeze_on_suspend {
+        let cgroup = some_or!(container_cgroup_stage0(), return None);
+        true_or!(freeze_on_suspend(&cgroup), return None);
+    }
+    if runs_slirp(stage, container) {
         true_or!(run_slirp(&container, &response), return None);
     }
-    ok_or!(stream.write_all(&[0u8]), return None);
+    if let Err(e) = ipc::proceed(&mut stream) {
+        msg_ret!("Failed to start {}: {}", runner.stage, e);
+    }
 
     if wait {
-        child.wait().ok()
+        let cgroup = if stage == 0 && !container.no_cgroup {
+            Some(some_or!(container_cgroup_stage0(), return None))
+        } else {
+            None
+        };
+        let stop = (stage == 0 && !container.keep_running).then(|| Stop {
+            timeout: Duration::from_secs(container.stop_timeout.into()),
+            cgroup: cgroup.as_deref(),
+        });
+        let exit_status = teardown::wait(&mut child, &signals, response.pid, stop);
+        ipc::relay_reports(&mut stream);
+        if let (Some(connection_log), Some(fd)) = (connection_log, container.net_log_fd) {
+            let connections = connection_log.finish();
+            let mut file = unsafe { File::from_raw_fd(fd) };
+            write_net_log(&mut file, &connections);
+        }
+        exit_status
     } else {
         Some(ExitStatus::from_raw(0))
     }
@@ -1,112 +1,49 @@
-use super::utils::{ro_bind_subentries_keep_symlinks, CLONE_NEWTIME};
-use super::{Bind, Container, ContainerRunner, ContainerRunnerResponse, Mount, Options, Stage};
+use super::plan::{mounting_args, next_container};
+use super::utils::{read_entries, unshare_time};
+use super::{Container, ContainerRunner, Stage};
 use crate::bwrap::bwrap;
 use crate::cgroup::{cgroup_init, cgroup_postexec, cgroup_preexec};
+use crate::ipc;
 use crate::socket_pair::{set_cloexec, socket_pair};
-use crate::{msg_ret, ok_or, some_or, true_or};
-use libc::{close, unshare};
+use crate::teardown::{self, block_signals};
+use crate::{debug, msg_ret, ok_or, some_or, some_or_ret, true_or, verbose};
+use libc::close;
 use std::ffi::OsString;
-use std::io::Write;
+use std::fs;
 use std::os::unix::process::ExitStatusExt;
 use std::process::ExitStatus;
+use std::time::Instant;
 
 pub fn run_container(
     container: &Container,
     env: &[(OsString, OsString)],
     wait: bool,
 ) -> Option<ExitStatus> {
-    true_or!(cgroup_init(false), return None);
+    if !container.no_cgroup {
+        true_or!(cgroup_init(false), return None);
+    }
 
     if !container.share_time {
-        true_or!(unsafe { unshare(CLONE_NEWTIME) } == 0, return None);
+        true_or!(unshare_time(container.time_offsets.as_ref()), return None);
     }
 
-    let mut args = Vec::<OsString>::new();
-    args.push("--unshare-user".into());
-    args.push("--unshare-ipc".into());
-    args.push("--unshare-pid".into());
-    args.push("--unshare-uts".into());
-    args.push("--unshare-cgroup".into());
-    args.push("--uid".into());
-    args.push("0".into());
-    args.push("--gid".into());
-    args.push("0".into());
-    args.push("--hostname".into());
-    args.push("container".into());
-    args.push("--chdir".into());
-    args.push("/".into());
-    args.push("--die-with-parent".into());
-    args.push("--cap-drop".into());
-    args.push("all".into());
-    args.push("--cap-add".into());
-    args.push("all".into());
-
-    args.append(&mut ok_or!(
-        ro_bind_subentries_keep_symlinks("/container_staging_image", "/"),
+    let entries = ok_or!(
+        read_entries("/container_staging_image"),
         msg_ret!("Failed binding staging image")
-    ));
-
-    args.push("--proc".into());
-    args.push("/proc".into());
-    args.push("--tmpfs".into());
-    args.push("/tmp".into());
-    args.push("--tmpfs".into());
-    args.push("/run".into());
-    args.push("--dir".into());
-    args.push("/root".into());
-    args.push("--dir".into());
-    args.push("/home".into());
-    args.push("--dir".into());
-    args.push("/home/user".into());
-    args.push("--dev".into());
-    args.push("/dev".into());
-    args.push("--mqueue".into());
-    args.push("/dev/mqueue".into());
-    args.push("--dev-bind".into());
-    args.push("/dev/fuse".into());
-    args.push("/dev/fuse".into());
-    args.push("--dev-bind".into());
-    args.push("/dev/net/tun".into());
-    args.push("/dev/net/tun".into());
-    args.push("--tmpfs".into());
-    args.push("/sys".into());
-
-    let mut bind_index: u64 = 0;
-    for option in &container.options {
-        match option {
-            Options::Symlink(Bind { src, dest }) => {
-                args.push("--symlink".into());
-                args.push(src.to_owned().into());
-                args.push(dest.to_owned().into());
-            }
-            Options::Dir(Mount { path }) => {
-                args.push("--dir".into());
-                args.push(path.to_owned().into());
-            }
-            _ => (),
-        }
-        let bind = match option {
-            Options::Bind(bind) => Some(bind),
-            Options::DevBind(bind) => Some(bind),
-            Options::RoBind(bind) => Some(bind),
-            _ => None,
-        };
-        match option {
-            Options::Bind(_) => args.push("--bind".into()),
-            Options::DevBind(_) => args.push("--dev-bind".into()),
-            Options::RoBind(_) => args.push("--ro-bind".into()),
-            _ => (),
-        }
-        if let Some(Bind { src: _, dest }) = bind {
-            args.push(("/container_bind_".to_owned() + &bind_index.to_string()).into());
-            args.push(dest.to_owned().into());
-            bind_index += 1;
-        }
+    );
+    let etc = read_entries("/container_staging_image/etc").unwrap_or_default();
+    let dbus = read_entries("/container_staging_image/var/lib/dbus").unwrap_or_default();
+    let (mut args, files) = match mounting_args(container, &entries, &etc, &dbus) {
+        Ok(result) => result,
+        Err(e) => msg_ret!("{}", e),
+    };
+    for (path, contents) in files {
+        ok_or!(
+            fs::write(&path, contents),
+            msg_ret!("Failed to write {}", path)
+        );
     }
 
-    args.push("--tmpfs".into());
-    args.push("/sys/fs/cgroup".into());
-
     let (mut stream, sock) = some_or!(socket_pair(), msg_ret!("Cannot create socket pair"));
     // TODO: Close the other socket on error
 
@@ -115,7 +52,11 @@ pub fn run_container(
     args.push("--inner".into());
     args.push(sock.to_string().into());
 
-    true_or!(cgroup_preexec(false), return None);
+    if !container.no_cgroup {
+        true_or!(cgroup_preexec(false), return None);
+    }
+    let signals = some_or!(block_signals(), return None);
+    let start = Instant::now();
     let result = bwrap(args, true);
     true_or!(unsafe { set_cloexec(sock) }, return None);
     unsafe { close(sock) };
@@ -128,27 +69,35 @@ pub fn run_container(
         }
     };
 
-    let mut container_clone = Container::default();
-    container_clone.unshare_user = container.unshare_user;
-    container_clone.create_dummy_files = container.create_dummy_files;
-    container_clone.command_before_unshare_user = container.command_before_unshare_user.clone();
-    container_clone.command = container.command.clone();
+    let container_clone = next_container(&Stage::Mounting, container);
 
     let runner = ContainerRunner {
         stage: Stage::Exec,
         container: container_clone,
         env: env.to_owned(),
     };
-    ok_or!(
-        stream.write_all(&ok_or!(bincode::serialize(&runner), return None)),
-        return None
+    debug!("Sending {:?}", runner);
+    let response = match ipc::start_stage(&mut stream, &runner) {
+        Ok(response) => some_or_ret!(response),
+        Err(e) => msg_ret!("Failed to start {}: {}", runner.stage, e),
+    };
+    verbose!(
+        "Started {} as pid {} after {:.6}s",
+        runner.stage,
+        response.pid,
+        start.elapsed().as_secs_f64()
     );
-    let _: ContainerRunnerResponse = ok_or!(bincode::deserialize_from(&mut stream), return None);
-    true_or!(cgroup_postexec(false), return None);
-    ok_or!(stream.write_all(&[0u8]), return None);
+    if !container.no_cgroup {
+        true_or!(cgroup_postexec(false), return None);
+    }
+    if let Err(e) = ipc::proceed(&mut stream) {
+        msg_ret!("Failed to start {}: {}", runner.stage, e);
+    }
 
     if wait {
-        child.wait().ok()
+        let exit_status = teardown::wait(&mut child, &signals, response.pid, None);
+        ipc::relay_reports(&mut stream);
+        exit_status
     } else {
         Some(ExitStatus::from_raw(0))
     }
@@ -0,0 +1,411 @@
+use super::utils::{ro_bind_filesystem, ro_bind_subentries_keep_symlinks};
+use crate::bwrap::{bwrap, bwrap_command};
+use crate::log::timestamp;
+use crate::nflog::{Destination, Nflog};
+use crate::{msg_and, msg_ret, msg_retf, ok_or, some_or, true_or};
+use indoc::indoc;
+use libc::{timeval, ENOBUFS};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// The nflog group of the new connections logged by `NET_LOG_RULES`.
+const NET_LOG_GROUP: u16 = 27493;
+
+/// Counts packets and bytes per remote address, protocol and port in both directions, and new
+/// connections per remote address, protocol and port. New connections are also logged to
+/// `NET_LOG_GROUP`, for `ConnectionLog`. The chains run at priority -10, so traffic dropped by
+/// chains of the user at priority 0 or later is counted as well, but not traffic dropped at an
+/// earlier priority.
+pub static NET_LOG_RULES: &str = indoc! {r#"
+    table inet keg_net_log {
+        set traffic4 {
+            type ipv4_addr . inet_proto . inet_service
+            flags dynamic
+            size 65536
+        }
+        set traffic6 {
+            type ipv6_addr . inet_proto . inet_service
+            flags dynamic
+            size 65536
+        }
+        set connections4 {
+            type ipv4_addr . inet_proto . inet_service
+            flags dynamic
+            size 65536
+        }
+        set connections6 {
+            type ipv6_addr . inet_proto . inet_service
+            flags dynamic
+            size 65536
+        }
+        chain output {
+            type filter hook output priority -10; policy accept;
+            meta l4proto { tcp, udp } update @traffic4 { ip daddr . meta l4proto . th dport counter }
+            meta l4proto { tcp, udp } update @traffic6 { ip6 daddr . meta l4proto . th dport counter }
+            meta l4proto { tcp, udp } ct state new update @connections4 { ip daddr . meta l4proto . th dport counter }
+            meta l4proto { tcp, udp } ct state new update @connections6 { ip6 daddr . meta l4proto . th dport counter }
+            meta l4proto { tcp, udp } ct state new log group 27493
+        }
+        chain input {
+            type filter hook input priority -10; policy accept;
+            meta l4proto { tcp, udp } update @traffic4 { ip saddr . meta l4proto . th sport counter }
+            meta l4proto { tcp, udp } update @traffic6 { ip6 saddr . meta l4proto . th sport counter }
+        }
+    }
+"#};
+
+fn nft_args(args: &mut Vec<OsString>) {
+    args.push("--unshare-ipc".into());
+    args.push("--unshare-pid".into());
+    args.push("--unshare-uts".into());
+    args.push("--unshare-cgroup".into());
+    args.push("--uid".into());
+    args.push("0".into());
+    args.push("--gid".into());
+    args.push("0".into());
+    args.push("--hostname".into());
+    args.push("".into());
+    args.push("--chdir".into());
+    args.push("/".into());
+}
+
+fn nft_cap_args(args: &mut Vec<OsString>) {
+    args.push("--die-with-parent".into());
+    args.push("--cap-drop".into());
+    args.push("all".into());
+    args.push("--cap-add".into());
+    args.push("cap_net_admin".into());
+    args.push("--".into());
+}
+
+pub fn run_nft(rules: &Path) -> bool {
+    let mut args = Vec::<OsString>::new();
+    nft_args(&mut args);
+    args.append(&mut ok_or!(
+        ro_bind_subentries_keep_symlinks("/container_staging_image", "/"),
+        msg_and!("Failed binding staging image"; return false)
+    ));
+    args.push("--ro-bind".into());
+    args.push(rules.to_owned().into());
+    args.push("/container_net_nft_rules".into());
+    nft_cap_args(&mut args);
+
+    args.push("/usr/sbin/nft".into());
+    args.push("-f".into());
+    args.push("/container_net_nft_rules".into());
+
+    let exit_status = ok_or!(ok_or!(bwrap(args, true), return false).wait(), return false);
+    true_or!(exit_status.success(), return false);
+    true
+}
+
+/// Returns the output of `nft list table inet keg_net_log`.
+fn list_net_log() -> Option<String> {
+    let mut args = Vec::<OsString>::new();
+    nft_args(&mut args);
+    args.append(&mut ok_or!(
+        ro_bind_subentries_keep_symlinks("/container_staging_image", "/"),
+        msg_ret!("Failed binding staging image")
+    ));
+    nft_cap_args(&mut args);
+
+    args.push("/usr/sbin/nft".into());
+    args.push("list".into());
+    args.push("table".into());
+    args.push("inet".into());
+    args.push("keg_net_log".into());
+
+    let output = ok_or!(
+        bwrap_command(args, true).stderr(Stdio::inherit()).output(),
+        msg_ret!("Failed to run nft")
+    );
+    true_or!(output.status.success(), msg_ret!("Failed to list net log"));
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parses elements such as `{ 1.1.1.1 . tcp . 443 counter packets 2 bytes 120, ... }` of the set
+/// `name` into a map from (address, protocol, port) to (packets, bytes).
+fn parse_set(listing: &str, name: &str) -> HashMap<(String, String, String), (u64, u64)> {
+    let mut result = HashMap::new();
+    let header = format!("set {name} {{");
+    let set = some_or!(listing.find(&header), return result) + header.len();
+    // Up to the next set or chain, since an empty set has no elements.
+    let listing: Vec<&str> = listing[set..]
+        .lines()
+        .take_while(|line| {
+            let line = line.trim_start();
+            !line.starts_with("set ") && !line.starts_with("chain ")
+        })
+        .collect();
+    let listing = listing.join("\n");
+    let start = some_or!(listing.find("elements = {"), return result) + "elements = {".len();
+    let end = some_or!(listing[start..].find('}'), return result) + start;
+    for element in listing[start..end].split(',') {
+        let tokens: Vec<&str> = element.split_whitespace().collect();
+        if let [address, ".", protocol, ".", port, "counter", "packets", packets, "bytes", bytes] =
+            tokens[..]
+        {
+            let packets = ok_or!(packets.parse(), continue);
+            let bytes = ok_or!(bytes.parse(), continue);
+            result.insert(
+                (address.to_owned(), protocol.to_owned(), port.to_owned()),
+                (packets, bytes),
+            );
+        }
+    }
+    result
+}
+
+/// A new connection of the container, as (time, destination).
+type Connection = (String, Destination);
+
+/// Records the new connections logged by `NET_LOG_RULES` in a thread, until `finish`.
+pub struct ConnectionLog {
+    finished: Arc<AtomicBool>,
+    thread: JoinHandle<Vec<Connection>>,
+}
+
+impl ConnectionLog {
+    /// Starts recording. The net log rules must be loaded in the network namespace of this process,
+    /// which must have CAP_NET_ADMIN in it.
+    pub fn start() -> Option<Self> {
+        // How long `finish` may wait for the thread.
+        let timeout = timeval {
+            tv_sec: 0,
+            tv_usec: 100_000,
+        };
+        let nflog = Nflog::bind(NET_LOG_GROUP, timeout)?;
+        let finished = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let finished = finished.clone();
+            move || {
+                let mut connections = Vec::new();
+                let mut lost = false;
+                loop {
+                    match nflog.recv() {
+                        Ok(destinations) => {
+                            connections.extend(destinations.into_iter().map(|x| (timestamp(), x)))
+                        }
+                        // The socket is drained after `finish`.
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            if finished.load(Ordering::Relaxed) {
+                                break;
+                            }
+                        }
+                        Err(e) if e.raw_os_error() == Some(ENOBUFS) => lost = true,
+                        Err(e) => {
+                            eprintln!("Failed to receive new connections: {e}");
+                            break;
+                        }
+                    }
+                }
+                if lost {
+                    eprintln!("Some new connections were not logged");
+                }
+                connections
+            }
+        });
+        Some(ConnectionLog { finished, thread })
+    }
+
+    /// Stops recording after the connections already logged, and returns them.
+    pub fn finish(self) -> Vec<Connection> {
+        self.finished.store(true, Ordering::Relaxed);
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+/// Returns the net log summary as tab-separated lines: the counters sorted by bytes, then the
+/// logged new connections in order.
+fn net_log_summary(listing: &str, logged: &[Connection]) -> String {
+    let mut traffic = parse_set(listing, "traffic4");
+    traffic.extend(parse_set(listing, "traffic6"));
+    let mut connections = parse_set(listing, "connections4");
+    connections.extend(parse_set(listing, "connections6"));
+
+    let mut lines: Vec<_> = traffic
+        .into_iter()
+        .map(|(key, (packets, bytes))| {
+            let new_connections = connections.get(&key).map_or(0, |(packets, _)| *packets);
+            (key, new_connections, packets, bytes)
+        })
+        .collect();
+    lines.sort_by(|a, b| b.3.cmp(&a.3).then_with(|| a.0.cmp(&b.0)));
+
+    let mut result = "# address\tprotocol\tport\tconnections\tpackets\tbytes\n".to_owned();
+    for ((address, protocol, port), new_connections, packets, bytes) in lines {
+        result.push_str(&format!(
+            "{address}\t{protocol}\t{port}\t{new_connections}\t{packets}\t{bytes}\n"
+        ));
+    }
+    result.push_str("\n# time\taddress\tprotocol\tport\n");
+    for (time, (address, protocol, port)) in logged {
+        result.push_str(&format!("{time}\t{address}\t{protocol}\t{port}\n"));
+    }
+    result
+}
+
+/// Reads the counters installed by `NET_LOG_RULES` and writes a summary with `connections` to
+/// `out`.
+pub fn write_net_log<W: Write>(out: &mut W, connections: &[Connection]) -> bool {
+    let listing = some_or!(list_net_log(), return false);
+    ok_or!(
+        out.write_all(net_log_summary(&listing, connections).as_bytes()),
+        msg_retf!("Failed to write net log")
+    );
+    true
+}
+
+/// Checks nftables rules with `nft --check` in a throwaway network namespace, using the same base
+/// image as the container. Diagnostics are printed with `path` as the file name.
+#[must_use]
+pub fn check_nft_rules(rules: &[u8], base_image: Option<&OsStr>, path: &Path) -> bool {
+    let mut args = Vec::<OsString>::new();
+    args.push("--unshare-user".into());
+    args.push("--unshare-net".into());
+    nft_args(&mut args);
+    let binds = match base_image {
+        Some(base_image) => ro_bind_subentries_keep_symlinks(base_image, "/"),
+        None => ro_bind_filesystem("/"),
+    };
+    args.append(&mut ok_or!(
+        binds,
+        msg_and!("Failed binding staging image"; return false)
+    ));
+    args.push("--proc".into());
+    args.push("/proc".into());
+    args.push("--dev".into());
+    args.push("/dev".into());
+    nft_cap_args(&mut args);
+
+    args.push("/usr/sbin/nft".into());
+    args.push("--check".into());
+    args.push("-f".into());
+    args.push("/dev/stdin".into());
+
+    let mut child = match bwrap_command(args, true)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => msg_retf!("Cannot run bwrap: {e}"),
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        ok_or!(
+            stdin.write_all(rules),
+            msg_retf!("Failed to send nft rules to nft")
+        );
+    }
+    let output = ok_or!(child.wait_with_output(), msg_retf!("Failed to run nft"));
+    if !output.status.success() {
+        let diagnostics = String::from_utf8_lossy(&output.stderr)
+            .replace("/dev/stdin:", &format!("{}:", path.display()));
+        eprint!("{diagnostics}");
+        msg_retf!("Invalid nft rules in \"{}\"", path.display());
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LISTING: &str = indoc! {"
+        table inet keg_net_log {
+        	set traffic4 {
+        		type ipv4_addr . inet_proto . inet_service
+        		size 65536
+        		flags dynamic
+        		elements = { 1.1.1.1 . tcp . 443 counter packets 10 bytes 5000,
+        			     9.9.9.9 . udp . 53 counter packets 2 bytes 120,
+        			     8.8.8.8 . udp counter packets 1 bytes 60,
+        			     8.8.4.4 . udp . 53 counter packets x bytes 60 }
+        	}
+
+        	set traffic6 {
+        		type ipv6_addr . inet_proto . inet_service
+        		size 65536
+        		flags dynamic
+        		elements = { 2001:db8::1 . tcp . 443 counter packets 3 bytes 900 }
+        	}
+
+        	set connections4 {
+        		type ipv4_addr . inet_proto . inet_service
+        		size 65536
+        		flags dynamic
+        	}
+
+        	set connections6 {
+        		type ipv6_addr . inet_proto . inet_service
+        		size 65536
+        		flags dynamic
+        		elements = { 2001:db8::1 . tcp . 443 counter packets 1 bytes 80 }
+        	}
+        }
+    "};
+
+    fn key(address: &str, protocol: &str, port: &str) -> (String, String, String) {
+        (address.to_owned(), protocol.to_owned(), port.to_owned())
+    }
+
+    #[test]
+    fn set_with_malformed_elements() {
+        let set = parse_set(LISTING, "traffic4");
+        assert_eq!(set.len(), 2);
+        assert_eq!(set[&key("1.1.1.1", "tcp", "443")], (10, 5000));
+        assert_eq!(set[&key("9.9.9.9", "udp", "53")], (2, 120));
+    }
+
+    #[test]
+    fn ipv6_set() {
+        let set = parse_set(LISTING, "traffic6");
+        assert_eq!(set.len(), 1);
+        assert_eq!(set[&key("2001:db8::1", "tcp", "443")], (3, 900));
+    }
+
+    #[test]
+    fn empty_set() {
+        // Not the elements of `connections6`.
+        assert!(parse_set(LISTING, "connections4").is_empty());
+        assert!(parse_set(LISTING, "missing").is_empty());
+        assert!(parse_set("", "traffic4").is_empty());
+    }
+
+    #[test]
+    fn summary() {
+        let connections = [
+            (
+                "1700000000.000001".to_owned(),
+                ("2001:db8::1".parse().unwrap(), "tcp", 443),
+            ),
+            (
+                "1700000000.500000".to_owned(),
+                ("9.9.9.9".parse().unwrap(), "udp", 53),
+            ),
+        ];
+        assert_eq!(
+            net_log_summary(LISTING, &connections),
+            "# address\tprotocol\tport\tconnections\tpackets\tbytes\n\
+             1.1.1.1\ttcp\t443\t0\t10\t5000\n\
+             2001:db8::1\ttcp\t443\t1\t3\t900\n\
+             9.9.9.9\tudp\t53\t0\t2\t120\n\
+             \n\
+             # time\taddress\tprotocol\tport\n\
+             1700000000.000001\t2001:db8::1\ttcp\t443\n\
+             1700000000.500000\t9.9.9.9\tudp\t53\n"
+        );
+    }
+
+    #[test]
+    fn rules_log_to_group() {
+        assert!(NET_LOG_RULES.contains(&format!("ct state new log group {NET_LOG_GROUP}\n")));
+    }
+}
@@ -0,0 +1,1139 @@
+//! What each stage does, built without side effects. The stages run these builders on what they
+//! find in their root, and `--dry-run` runs them on what stage 0 would find to print the plan.
+
+use super::utils::{ro_bind_entries, Entries};
+use super::{Bind, Container, Mount, Options, SetEnv, Stage, DEFAULT_HOSTNAME};
+use crate::dummy_proc;
+use crate::etc_files;
+use crate::json;
+use crate::log::command_line;
+use crate::masked_paths::{PRIVATE_IDENTITY_ETC_FILES, PRIVATE_IDENTITY_PROC_FILES};
+use libc::{gid_t, uid_t};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Returns the environment of the command, from the environment `env` of keg.
+pub fn process_env(
+    container: &Container,
+    env: &[(OsString, OsString)],
+) -> Vec<(OsString, OsString)> {
+    let mut env_map = HashMap::new();
+    env_map.insert(
+        "PATH".into(),
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".into(),
+    );
+
+    if container.keep_env {
+        env_map = HashMap::with_capacity(env.len());
+        for (k, v) in env {
+            env_map.insert(k.to_owned(), v.to_owned());
+        }
+    }
+    for option in &container.options {
+        match option {
+            Options::SetEnv(SetEnv { key, value }) => {
+                env_map.insert(key.to_owned(), value.to_owned());
+            }
+            Options::UnsetEnv(key) => {
+                env_map.remove(key);
+            }
+            _ => (),
+        };
+    }
+    env_map.into_iter().collect()
+}
+
+fn cleanup_container(container: &mut Container) {
+    container.keep_env = false;
+    container.base_image = None;
+    container.stats = false;
+    container.stats_file = None;
+    container.freeze_on_suspend = false;
+    container.keep_running = false;
+    container.options.retain_mut(|option| match option {
+        Options::SetEnv(_) | Options::UnsetEnv(_) => false,
+        Options::Bind(Bind { src, dest: _ }) => {
+            *src = "".into();
+            true
+        }
+        Options::DevBind(Bind { src, dest: _ }) => {
+            *src = "".into();
+            true
+        }
+        Options::RoBind(Bind { src, dest: _ }) => {
+            *src = "".into();
+            true
+        }
+        Options::Symlink(_) => true,
+        Options::Dir(_) => true,
+    });
+}
+
+/// Returns the container sent to the stage after `stage`.
+pub fn next_container(stage: &Stage, container: &Container) -> Container {
+    let mut container_clone = container.clone();
+    match stage {
+        Stage::Isolation(0) => {
+            // Remove information we already applied.
+            cleanup_container(&mut container_clone);
+        }
+        Stage::Isolation(4) => {
+            // nft rules already applied.
+            container_clone.net_nft_rules = Vec::new();
+            container_clone.net_log_fd = None;
+        }
+        Stage::Mounting => {
+            container_clone = Container::default();
+            container_clone.no_cgroup = container.no_cgroup;
+            container_clone.unshare_user = container.unshare_user;
+            container_clone.init = container.init;
+            container_clone.log_level = container.log_level;
+            container_clone.log_fd = container.log_fd;
+            container_clone.command_before_unshare_user =
+                container.command_before_unshare_user.clone();
+            container_clone.command = container.command.clone();
+        }
+        _ => (),
+    }
+    if let Stage::Isolation(1..) = stage {
+        // The time namespace was unshared by this stage, and is inherited.
+        container_clone.share_time = true;
+    }
+    container_clone
+}
+
+/// Returns whether the traffic of the container is filtered or logged with nft, which needs
+/// isolation stages 2 to 5.
+fn uses_nft(container: &Container) -> bool {
+    !container.net_nft_rules.is_empty() || container.net_log_fd.is_some()
+}
+
+/// Returns the stage started by `stage`.
+///
+/// Without a network to reach, i.e. with `join_net`, or without `share_net` or nft, stage 0 starts
+/// stage 6 directly, as stages 1 to 5 would only connect it to the network.
+///
+/// With `share_net`, all stages run even without nft: the slirp4netns of stage 5, which the
+/// container talks to, then runs in the network namespace of stage 4 behind two more slirp4netns.
+/// Starting stage 6 from stage 1 would be faster, but would run it in the network namespace of
+/// the host, so that escaping it would reach the host and its loopback.
+pub fn next_stage(stage: &Stage, container: &Container) -> Stage {
+    match stage {
+        Stage::Isolation(0)
+            if container.join_net.is_some() || !(container.share_net || uses_nft(container)) =>
+        {
+            Stage::Isolation(6)
+        }
+        Stage::Isolation(6) => Stage::Mounting,
+        Stage::Isolation(stage) => Stage::Isolation(stage + 1),
+        Stage::Mounting | Stage::Exec => Stage::Exec,
+    }
+}
+
+/// Returns whether isolation stage `stage` runs slirp4netns for the next stage.
+pub fn runs_slirp(stage: u8, container: &Container) -> bool {
+    container.join_net.is_none() && (stage == 1 || stage == 3 || stage == 5)
+}
+
+/// Returns the bwrap arguments of isolation stage `stage` before the command. `entries` are the
+/// entries of the base image in stage 0, and of `/container_staging_image` after.
+pub fn isolation_args(
+    stage: u8,
+    container: &Container,
+    entries: &Entries,
+    keg_bin: &Path,
+) -> Vec<OsString> {
+    let mut args = Vec::<OsString>::new();
+    args.push("--unshare-user".into());
+    args.push("--unshare-ipc".into());
+    if stage != 1 && stage != 3 && stage != 5 {
+        args.push("--unshare-pid".into());
+    }
+    if container.join_net.is_none()
+        && (!container.share_net || (stage != 0 && stage != 2 && stage != 4 && stage != 6))
+    {
+        args.push("--unshare-net".into());
+    }
+    args.push("--unshare-uts".into());
+    args.push("--unshare-cgroup".into());
+    args.push("--uid".into());
+    args.push("0".into());
+    args.push("--gid".into());
+    args.push("0".into());
+    args.push("--hostname".into());
+    args.push(
+        container
+            .hostname
+            .clone()
+            .unwrap_or_else(|| DEFAULT_HOSTNAME.into()),
+    );
+    args.push("--chdir".into());
+    args.push("/".into());
+    args.push("--die-with-parent".into());
+    args.push("--cap-drop".into());
+    args.push("all".into());
+    args.push("--cap-add".into());
+    args.push("cap_setfcap".into());
+    args.push("--cap-add".into());
+    args.push("cap_sys_admin".into());
+    if stage == 3 {
+        args.push("--cap-add".into());
+        args.push("cap_net_admin".into());
+    }
+    if stage == 0 && container.time_offsets.is_some() && !container.share_time {
+        // The next stage unshares the time namespace and sets its clock offsets.
+        args.push("--cap-add".into());
+        args.push("cap_sys_time".into());
+    }
+
+    let staging = Path::new("/container_staging_image");
+    if stage == 0 {
+        let src = container.base_image.as_deref().unwrap_or(OsStr::new("/"));
+        args.append(&mut ro_bind_entries(
+            Path::new(src),
+            entries,
+            Path::new("/"),
+        ));
+        args.append(&mut ro_bind_entries(Path::new(src), entries, staging));
+        args.push("--ro-bind".into());
+        args.push(keg_bin.into());
+        args.push("/keg-bin".into());
+        args.push("--ro-bind".into());
+        args.push(keg_bin.into());
+        args.push("/container_staging_image/keg-bin".into());
+    } else {
+        args.append(&mut ro_bind_entries(staging, entries, Path::new("/")));
+        args.append(&mut ro_bind_entries(staging, entries, staging));
+    }
+
+    args.push("--proc".into());
+    args.push("/proc".into());
+    args.push("--tmpfs".into());
+    args.push("/tmp".into());
+    args.push("--dev".into());
+    args.push("/dev".into());
+    args.push("--mqueue".into());
+    args.push("/dev/mqueue".into());
+    args.push("--dev-bind".into());
+    args.push("/dev/fuse".into());
+    args.push("/dev/fuse".into());
+    args.push("--dev-bind".into());
+    args.push("/dev/net/tun".into());
+    args.push("/dev/net/tun".into());
+
+    let mut bind_index: u64 = 0;
+    for option in &container.options {
+        // Binds
+        let bind = match option {
+            Options::Bind(bind) => Some(bind),
+            Options::DevBind(bind) => Some(bind),
+            Options::RoBind(bind) => Some(bind),
+            _ => None,
+        };
+        match option {
+            Options::Bind(_) => args.push("--bind".into()),
+            Options::DevBind(_) => args.push("--dev-bind".into()),
+            Options::RoBind(_) => args.push("--ro-bind".into()),
+            _ => (),
+        }
+        if let Some(Bind { src, dest: _ }) = bind {
+            if stage == 0 {
+                args.push(src.clone());
+            } else {
+                args.push(("/container_bind_".to_owned() + &bind_index.to_string()).into());
+            }
+            args.push(("/container_bind_".to_owned() + &bind_index.to_string()).into());
+            bind_index += 1;
+        }
+    }
+    args
+}
+
+/// Returns the arguments of slirp4netns for the network namespace of `pid`.
+pub fn slirp_args(container: &Container, ready_fd: &str, pid: &str) -> Vec<OsString> {
+    let mut args = Vec::<OsString>::new();
+    args.push("--configure".into());
+    args.push("--ready-fd".into());
+    args.push(ready_fd.into());
+    if !container.slirp.disable_ipv6 {
+        args.push("--enable-ipv6".into());
+    }
+    if let Some(cidr) = &container.slirp.cidr {
+        args.push("--cidr".into());
+        args.push(cidr.clone());
+    }
+    if let Some(mtu) = container.slirp.mtu {
+        args.push("--mtu".into());
+        args.push(mtu.to_string().into());
+    }
+    args.push("--disable-host-loopback".into());
+    args.push(pid.into());
+    args.push("tap0".into());
+    args
+}
+
+/// How a generated `/etc` file is bound in the final container.
+#[derive(Clone, Copy, PartialEq)]
+enum EtcBind {
+    /// Over the file of the base image, following symlinks once. Skipped if it is missing.
+    Optional,
+    /// Like `Optional`, but the base image must have the file.
+    Required,
+    /// As the entry of `/etc` itself, which is created if missing. Symlinks are not followed, so
+    /// that their target, e.g. the zone data of `/etc/localtime`, is left unchanged.
+    Replace,
+}
+
+/// Returns generated `/etc` files as (file name, contents, how it is bound).
+fn etc_files(container: &Container) -> Vec<(&'static str, Vec<u8>, EtcBind)> {
+    let mut files = Vec::new();
+    if container.hostname.is_some() || !container.hosts.is_empty() {
+        let hostname = container
+            .hostname
+            .as_deref()
+            .unwrap_or(OsStr::new(DEFAULT_HOSTNAME));
+        files.push(("hostname", etc_files::hostname(hostname), EtcBind::Optional));
+        files.push((
+            "hosts",
+            etc_files::hosts(hostname, &container.hosts),
+            EtcBind::Required,
+        ));
+    }
+    if !container.dns.is_empty() {
+        files.push((
+            "resolv.conf",
+            etc_files::resolv_conf(&container.dns),
+            EtcBind::Required,
+        ));
+    } else if let Some(dns_upstream) = &container.slirp.dns_upstream {
+        // Used by the DNS forwarder of podman's slirp4netns.
+        files.push((
+            "resolv.conf",
+            etc_files::resolv_conf(&[*dns_upstream]),
+            EtcBind::Required,
+        ));
+    }
+    if container.private_identity {
+        for file_name in PRIVATE_IDENTITY_ETC_FILES {
+            files.push((file_name, Vec::new(), EtcBind::Optional));
+        }
+    }
+    if container.hermetic {
+        files.push(("timezone", etc_files::timezone(), EtcBind::Replace));
+        files.push(("localtime", etc_files::utc_localtime(), EtcBind::Replace));
+    }
+    files
+}
+
+/// Returns where a file in `/etc` of the final container should be bind mounted, given the
+/// entries of `/etc` in the base image. Symlinks such as
+/// `/etc/resolv.conf -> /run/systemd/resolve/stub-resolv.conf` are followed once.
+fn etc_bind_dest(file_name: &str, etc: &Entries) -> Option<PathBuf> {
+    let (_, symlink) = etc.iter().find(|(name, _)| name == file_name)?;
+    match symlink {
+        Some(target) => Some(Path::new("/etc").join(target)),
+        None => Some(Path::new("/etc").join(file_name)),
+    }
+}
+
+/// Returns whether the base image has its own `/var/lib/dbus/machine-id`, given the entries of
+/// `/var/lib/dbus`. A symlink, usually to `/etc/machine-id`, is hidden with its target.
+fn has_dbus_machine_id(dbus: &Entries) -> bool {
+    dbus.iter()
+        .any(|(name, symlink)| name == "machine-id" && symlink.is_none())
+}
+
+/// Files generated by the mounting stage, as (path, contents).
+pub type Files = Vec<(String, Vec<u8>)>;
+
+/// Returns the bwrap arguments of the mounting stage before the command, and the generated files
+/// it binds. `entries` are the entries of `/container_staging_image`, `etc` those of
+/// `/container_staging_image/etc`, and `dbus` those of `/container_staging_image/var/lib/dbus`.
+pub fn mounting_args(
+    container: &Container,
+    entries: &Entries,
+    etc: &Entries,
+    dbus: &Entries,
+) -> Result<(Vec<OsString>, Files), String> {
+    let mut args = Vec::<OsString>::new();
+    let mut files = Vec::new();
+    args.push("--unshare-user".into());
+    args.push("--unshare-ipc".into());
+    args.push("--unshare-pid".into());
+    args.push("--unshare-uts".into());
+    args.push("--unshare-cgroup".into());
+    args.push("--uid".into());
+    args.push("0".into());
+    args.push("--gid".into());
+    args.push("0".into());
+    args.push("--hostname".into());
+    args.push(
+        container
+            .hostname
+            .clone()
+            .unwrap_or_else(|| DEFAULT_HOSTNAME.into()),
+    );
+    args.push("--chdir".into());
+    args.push("/".into());
+    args.push("--die-with-parent".into());
+    args.push("--cap-drop".into());
+    args.push("all".into());
+    args.push("--cap-add".into());
+    args.push("all".into());
+
+    let etc_files = etc_files(container);
+    let replaced: Vec<_> = etc_files
+        .iter()
+        .filter(|(_, _, bind)| *bind == EtcBind::Replace)
+        .map(|(file_name, _, _)| *file_name)
+        .collect();
+    if replaced.is_empty() {
+        args.append(&mut ro_bind_entries(
+            Path::new("/container_staging_image"),
+            entries,
+            Path::new("/"),
+        ));
+    } else {
+        // `/etc` is rebuilt from its entries on a tmpfs, which is read-only like the bound `/etc`.
+        let entries: Entries = entries
+            .iter()
+            .filter(|(file_name, _)| file_name != "etc")
+            .cloned()
+            .collect();
+        args.append(&mut ro_bind_entries(
+            Path::new("/container_staging_image"),
+            &entries,
+            Path::new("/"),
+        ));
+        let etc: Entries = etc
+            .iter()
+            .filter(|(file_name, _)| !replaced.iter().any(|x| file_name == x))
+            .cloned()
+            .collect();
+        args.push("--tmpfs".into());
+        args.push("/etc".into());
+        args.append(&mut ro_bind_entries(
+            Path::new("/container_staging_image/etc"),
+            &etc,
+            Path::new("/etc"),
+        ));
+        for file_name in &replaced {
+            args.push("--ro-bind".into());
+            args.push(format!("/container_etc_{file_name}").into());
+            args.push(Path::new("/etc").join(file_name).into());
+        }
+        args.push("--remount-ro".into());
+        args.push("/etc".into());
+    }
+
+    args.push("--proc".into());
+    args.push("/proc".into());
+    args.push("--tmpfs".into());
+    args.push("/tmp".into());
+    args.push("--tmpfs".into());
+    args.push("/run".into());
+    args.push("--dir".into());
+    args.push("/root".into());
+    args.push("--dir".into());
+    args.push("/home".into());
+    args.push("--dir".into());
+    args.push("/home/user".into());
+    args.push("--dev".into());
+    args.push("/dev".into());
+    args.push("--mqueue".into());
+    args.push("/dev/mqueue".into());
+    args.push("--dev-bind".into());
+    args.push("/dev/fuse".into());
+    args.push("/dev/fuse".into());
+    args.push("--dev-bind".into());
+    args.push("/dev/net/tun".into());
+    args.push("/dev/net/tun".into());
+    args.push("--tmpfs".into());
+    args.push("/sys".into());
+
+    for (file_name, contents, bind) in etc_files {
+        let src = "/container_etc_".to_owned() + file_name;
+        if bind != EtcBind::Replace {
+            let dest = match etc_bind_dest(file_name, etc) {
+                Some(dest) => dest,
+                None if bind == EtcBind::Required => {
+                    return Err(format!("Cannot find /etc/{file_name} in the base image"))
+                }
+                None => continue,
+            };
+            args.push("--ro-bind".into());
+            args.push(src.clone().into());
+            args.push(dest.into());
+        }
+        files.push((src, contents));
+    }
+
+    if container.private_identity {
+        for file_name in PRIVATE_IDENTITY_PROC_FILES {
+            let src = "/container_proc_".to_owned() + file_name;
+            args.push("--ro-bind".into());
+            args.push(src.clone().into());
+            args.push(Path::new("/proc").join(file_name).into());
+            files.push((src, Vec::new()));
+        }
+    }
+
+    if let Some(dummy_proc) = &container.dummy_proc {
+        for file_name in dummy_proc::file_names(dummy_proc) {
+            let src = "/container_dummy_".to_owned() + file_name;
+            let dests = if !dummy_proc.in_place {
+                vec![PathBuf::from(&src)]
+            } else if file_name == "machine-id" {
+                // Nothing to hide where the base image has no machine ID.
+                let mut dests: Vec<_> = etc_bind_dest(file_name, etc).into_iter().collect();
+                if has_dbus_machine_id(dbus) {
+                    dests.push(dummy_proc::DBUS_MACHINE_ID.into());
+                }
+                dests
+            } else {
+                dummy_proc::dests(file_name)
+            };
+            if dests.is_empty() {
+                continue;
+            }
+            for dest in dests {
+                args.push("--ro-bind".into());
+                args.push(src.clone().into());
+                args.push(dest.into());
+            }
+            files.push((src, dummy_proc::contents(file_name, dummy_proc)));
+        }
+    }
+
+    let mut bind_index: u64 = 0;
+    for option in &container.options {
+        match option {
+            Options::Symlink(Bind { src, dest }) => {
+                args.push("--symlink".into());
+                args.push(src.to_owned());
+                args.push(dest.to_owned());
+            }
+            Options::Dir(Mount { path }) => {
+                args.push("--dir".into());
+                args.push(path.to_owned());
+            }
+            _ => (),
+        }
+        let bind = match option {
+            Options::Bind(bind) => Some(bind),
+            Options::DevBind(bind) => Some(bind),
+            Options::RoBind(bind) => Some(bind),
+            _ => None,
+        };
+        match option {
+            Options::Bind(_) => args.push("--bind".into()),
+            Options::DevBind(_) => args.push("--dev-bind".into()),
+            Options::RoBind(_) => args.push("--ro-bind".into()),
+            _ => (),
+        }
+        if let Some(Bind { src: _, dest }) = bind {
+            args.push(("/container_bind_".to_owned() + &bind_index.to_string()).into());
+            args.push(dest.to_owned());
+            bind_index += 1;
+        }
+    }
+
+    args.push("--tmpfs".into());
+    args.push("/sys/fs/cgroup".into());
+    Ok((args, files))
+}
+
+/// What a stage before the exec stage does.
+#[derive(Clone, PartialEq, Debug)]
+pub struct StagePlan {
+    pub stage: Stage,
+    /// Done by the stage, in order, other than running bwrap.
+    pub actions: Vec<String>,
+    /// Arguments of bwrap, which runs the next stage.
+    pub bwrap_args: Vec<OsString>,
+}
+
+/// What the exec stage does.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExecPlan {
+    pub env: Vec<(OsString, OsString)>,
+    pub command_before_unshare_user: Vec<OsString>,
+    pub unshare_user: Option<(uid_t, gid_t)>,
+    /// Whether the command runs under an init process.
+    pub init: bool,
+    pub command: Vec<OsString>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Plan {
+    pub stages: Vec<StagePlan>,
+    pub exec: ExecPlan,
+}
+
+/// Returns the entries of `/container_staging_image` in the isolation stages after stage 0, which
+/// binds `entries` of the base image and `keg-bin` there.
+pub fn staging_entries(entries: &Entries) -> Entries {
+    let mut result = entries.clone();
+    result.push(("keg-bin".into(), None));
+    result.sort_unstable();
+    result
+}
+
+fn time_offsets_action(container: &Container) -> Option<String> {
+    let offsets = container.time_offsets?;
+    Some(format!(
+        "Set the clock offsets: monotonic {:.3}s, boottime {:.3}s",
+        offsets.monotonic as f64 / 1e9,
+        offsets.boottime as f64 / 1e9
+    ))
+}
+
+/// Returns what every stage does. `entries` are the entries of the base image, `etc` those of its
+/// `/etc`, and `keg_bin` the keg binary.
+pub fn plan(
+    container: &Container,
+    env: &[(OsString, OsString)],
+    entries: &Entries,
+    etc: &Entries,
+    dbus: &Entries,
+    keg_bin: &Path,
+) -> Result<Plan, String> {
+    let mut env = process_env(container, env);
+    env.sort_unstable();
+    let inner_entries = staging_entries(entries);
+    let mut container = container.clone();
+    let mut stages = Vec::new();
+
+    let mut next = Stage::Isolation(0);
+    while let Stage::Isolation(stage) = next {
+        let mut actions = Vec::new();
+        if stage == 0 {
+            if let Some(pid) = container.join_net {
+                actions.push(format!("Join the network namespace of keg process {pid}"));
+            }
+            actions.push("Apply seccomp rules".to_owned());
+            actions.push("Join a new keyring session".to_owned());
+        } else if !container.share_time {
+            actions.push("Unshare the time namespace".to_owned());
+            actions.extend(time_offsets_action(&container));
+        }
+        if stage == 4 {
+            actions.push(format!(
+                "Load nft rules ({} bytes)",
+                container.net_nft_rules.len()
+            ));
+            if container.net_log_fd.is_some() {
+                actions.push("Load the net log rules".to_owned());
+                actions.push("Receive the new connections logged by the net log rules".to_owned());
+            }
+        }
+        let stage_entries = if stage == 0 { entries } else { &inner_entries };
+        let mut bwrap_args = isolation_args(stage, &container, stage_entries, keg_bin);
+        bwrap_args.extend(["--", "/keg-bin", "--inner", "<fd>"].map(OsString::from));
+        if runs_slirp(stage, &container) {
+            let args = slirp_args(&container, "<fd>", "<pid of the next stage>");
+            let mut action = "Run ".to_owned();
+            action.push_str(&command_line(
+                OsStr::new("/usr/bin/slirp4netns"),
+                args.iter().map(|x| &x[..]),
+            ));
+            if let Some(dns_upstream) = &container.slirp.dns_upstream {
+                action.push_str(&format!(", forwarding DNS to {dns_upstream}"));
+            }
+            actions.push(action);
+        }
+        if stage == 4 && container.net_log_fd.is_some() {
+            actions.push("Write the net log after the next stage exits".to_owned());
+        }
+        stages.push(StagePlan {
+            stage: Stage::Isolation(stage),
+            actions,
+            bwrap_args,
+        });
+        next = next_stage(&Stage::Isolation(stage), &container);
+        container = next_container(&Stage::Isolation(stage), &container);
+    }
+
+    let mut actions = Vec::new();
+    if !container.share_time {
+        actions.push("Unshare the time namespace".to_owned());
+        actions.extend(time_offsets_action(&container));
+    }
+    let (mut bwrap_args, files) = mounting_args(&container, &inner_entries, etc, dbus)?;
+    for (path, contents) in &files {
+        actions.push(format!("Write {path} ({} bytes)", contents.len()));
+    }
+    bwrap_args.extend(["--", "/keg-bin", "--inner", "<fd>"].map(OsString::from));
+    stages.push(StagePlan {
+        stage: Stage::Mounting,
+        actions,
+        bwrap_args,
+    });
+    container = next_container(&Stage::Mounting, &container);
+
+    Ok(Plan {
+        stages,
+        exec: ExecPlan {
+            env,
+            command_before_unshare_user: container.command_before_unshare_user.clone(),
+            unshare_user: container.unshare_user,
+            init: container.init,
+            command: container.command.clone(),
+        },
+    })
+}
+
+/// bwrap arguments of a stage, grouped.
+#[derive(Default)]
+struct Summary {
+    namespaces: Vec<String>,
+    capabilities: Vec<String>,
+    options: Vec<String>,
+    mounts: Vec<String>,
+}
+
+fn summarize(args: &[OsString]) -> Summary {
+    let mut summary = Summary::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if let Some(namespace) = arg.strip_prefix("--unshare-") {
+            summary.namespaces.push(namespace.to_owned());
+            continue;
+        }
+        let arity = match &arg[..] {
+            "--ro-bind" | "--bind" | "--dev-bind" | "--symlink" => 2,
+            "--dir" | "--tmpfs" | "--proc" | "--dev" | "--mqueue" | "--remount-ro" => 1,
+            "--uid" | "--gid" | "--hostname" | "--chdir" | "--cap-add" | "--cap-drop" => 1,
+            _ => 0,
+        };
+        let operands: Vec<&OsString> = args.by_ref().take(arity).collect();
+        match &arg[..] {
+            "--cap-add" => summary
+                .capabilities
+                .extend(operands.iter().map(|x| x.to_string_lossy().into_owned())),
+            "--cap-drop" => (),
+            "--uid" | "--gid" | "--hostname" | "--chdir" | "--die-with-parent" => {
+                summary.options.push(command_line(
+                    OsStr::new(&arg[..]),
+                    operands.into_iter().map(|x| &x[..]),
+                ))
+            }
+            _ => summary.mounts.push(command_line(
+                OsStr::new(&arg[..]),
+                operands.into_iter().map(|x| &x[..]),
+            )),
+        }
+    }
+    summary
+}
+
+fn env_line((key, value): &(OsString, OsString)) -> String {
+    format!("{}={}", key.to_string_lossy(), value.to_string_lossy())
+}
+
+/// Returns `plan` as text.
+pub fn to_text(plan: &Plan) -> String {
+    let mut out = String::new();
+    for stage in &plan.stages {
+        let summary = summarize(&stage.bwrap_args);
+        out.push_str(&format!("{}:\n", stage.stage));
+        if !stage.actions.is_empty() {
+            out.push_str("  Actions:\n");
+            for action in &stage.actions {
+                out.push_str(&format!("    {action}\n"));
+            }
+        }
+        out.push_str(&format!("  Namespaces: {}\n", summary.namespaces.join(" ")));
+        out.push_str(&format!(
+            "  Capabilities: {}\n",
+            summary.capabilities.join(" ")
+        ));
+        out.push_str(&format!("  Options: {}\n", summary.options.join(" ")));
+        out.push_str("  Mounts:\n");
+        for mount in &summary.mounts {
+            out.push_str(&format!("    {mount}\n"));
+        }
+        out.push_str(&format!(
+            "  Command: {}\n",
+            command_line(
+                OsStr::new("/usr/bin/bwrap"),
+                stage.bwrap_args.iter().map(|x| &x[..])
+            )
+        ));
+    }
+    let exec = &plan.exec;
+    out.push_str(&format!("{}:\n", Stage::Exec));
+    out.push_str("  Environment:\n");
+    for var in &exec.env {
+        out.push_str(&format!("    {}\n", env_line(var)));
+    }
+    if let Some((program, args)) = exec.command_before_unshare_user.split_first() {
+        out.push_str(&format!(
+            "  Command before unshare user: {}\n",
+            command_line(program, args.iter().map(|x| &x[..]))
+        ));
+    }
+    if let Some((uid, gid)) = exec.unshare_user {
+        out.push_str(&format!("  Unshare user: uid {uid}, gid {gid}\n"));
+    }
+    if exec.init {
+        out.push_str("  Init: keg\n");
+    }
+    if let Some((program, args)) = exec.command.split_first() {
+        out.push_str(&format!(
+            "  Command: {}\n",
+            command_line(program, args.iter().map(|x| &x[..]))
+        ));
+    }
+    out
+}
+
+fn json_os_strings<'a, I>(out: &mut String, items: I)
+where
+    I: IntoIterator<Item = &'a OsString>,
+{
+    json::push_array(out, items.into_iter().map(|x| x.to_string_lossy()));
+}
+
+/// Returns `plan` as JSON.
+pub fn to_json(plan: &Plan) -> String {
+    let mut out = String::from("{\"stages\":[");
+    for (i, stage) in plan.stages.iter().enumerate() {
+        let summary = summarize(&stage.bwrap_args);
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"stage\":");
+        json::push_string(&mut out, &stage.stage.to_string());
+        out.push_str(",\"actions\":");
+        json::push_array(&mut out, stage.actions.iter());
+        out.push_str(",\"namespaces\":");
+        json::push_array(&mut out, summary.namespaces.iter());
+        out.push_str(",\"capabilities\":");
+        json::push_array(&mut out, summary.capabilities.iter());
+        out.push_str(",\"options\":");
+        json::push_array(&mut out, summary.options.iter());
+        out.push_str(",\"mounts\":");
+        json::push_array(&mut out, summary.mounts.iter());
+        out.push_str(",\"bwrap_args\":");
+        json_os_strings(&mut out, &stage.bwrap_args);
+        out.push('}');
+    }
+    let exec = &plan.exec;
+    out.push_str("],\"exec\":{\"env\":");
+    json::push_array(&mut out, exec.env.iter().map(env_line));
+    out.push_str(",\"command_before_unshare_user\":");
+    json_os_strings(&mut out, &exec.command_before_unshare_user);
+    out.push_str(",\"unshare_user\":");
+    match exec.unshare_user {
+        Some((uid, gid)) => out.push_str(&format!("{{\"uid\":{uid},\"gid\":{gid}}}")),
+        None => out.push_str("null"),
+    }
+    out.push_str(&format!(",\"init\":{}", exec.init));
+    out.push_str(",\"command\":");
+    json_os_strings(&mut out, &exec.command);
+    out.push_str("}}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::{DummyProc, Host, Slirp, TimeOffsets};
+    use crate::golden;
+
+    fn entries() -> Entries {
+        vec![
+            ("bin".into(), Some("usr/bin".into())),
+            ("etc".into(), None),
+            ("usr".into(), None),
+        ]
+    }
+
+    fn etc() -> Entries {
+        vec![
+            ("fstab".into(), None),
+            ("hostname".into(), None),
+            ("hosts".into(), None),
+            (
+                "localtime".into(),
+                Some("/usr/share/zoneinfo/Europe/Berlin".into()),
+            ),
+            ("machine-id".into(), None),
+            (
+                "resolv.conf".into(),
+                Some("../run/systemd/resolve/stub-resolv.conf".into()),
+            ),
+        ]
+    }
+
+    fn dbus() -> Entries {
+        vec![("machine-id".into(), None)]
+    }
+
+    fn env() -> Vec<(OsString, OsString)> {
+        vec![
+            ("HOME".into(), "/home/me".into()),
+            ("PATH".into(), "/home/me/bin:/usr/bin".into()),
+        ]
+    }
+
+    fn container() -> Container {
+        Container {
+            command: vec!["/bin/true".into()],
+            ..Container::default()
+        }
+    }
+
+    fn check(name: &str, container: &Container) {
+        let plan = plan(
+            container,
+            &env(),
+            &entries(),
+            &etc(),
+            &dbus(),
+            Path::new("/usr/local/bin/keg"),
+        )
+        .unwrap();
+        golden::check(&format!("plan_{name}.txt"), &to_text(&plan));
+    }
+
+    #[test]
+    fn default() {
+        check("default", &container());
+    }
+
+    #[test]
+    fn default_json() {
+        let plan = plan(
+            &container(),
+            &env(),
+            &entries(),
+            &etc(),
+            &dbus(),
+            Path::new("/usr/local/bin/keg"),
+        )
+        .unwrap();
+        golden::check("plan_default.json", &to_json(&plan));
+    }
+
+    #[test]
+    fn share_net() {
+        let container = Container {
+            share_net: true,
+            slirp: Slirp {
+                cidr: Some("10.1.0.0/24".into()),
+                mtu: Some(1500),
+                disable_ipv6: true,
+                dns_upstream: Some("10.1.0.3".parse().unwrap()),
+            },
+            ..container()
+        };
+        check("share_net", &container);
+    }
+
+    #[test]
+    fn share_net_nft() {
+        let container = Container {
+            share_net: true,
+            net_nft_rules: b"table inet t {}\n".to_vec(),
+            net_log_fd: Some(3),
+            ..container()
+        };
+        check("share_net_nft", &container);
+    }
+
+    #[test]
+    fn nft_without_share_net() {
+        let container = Container {
+            net_nft_rules: b"table inet t {}\n".to_vec(),
+            ..container()
+        };
+        check("nft_without_share_net", &container);
+    }
+
+    #[test]
+    fn join_net() {
+        let container = Container {
+            join_net: Some(1234),
+            ..container()
+        };
+        check("join_net", &container);
+    }
+
+    #[test]
+    fn share_time() {
+        let container = Container {
+            share_net: true,
+            share_time: true,
+            ..container()
+        };
+        check("share_time", &container);
+    }
+
+    #[test]
+    fn hermetic() {
+        let container = Container {
+            hermetic: true,
+            hostname: Some(DEFAULT_HOSTNAME.into()),
+            time_offsets: Some(TimeOffsets {
+                monotonic: -1_500_000_000,
+                boottime: 2_000_000_000,
+            }),
+            ..container()
+        };
+        check("hermetic", &container);
+    }
+
+    #[test]
+    fn private_identity() {
+        let container = Container {
+            private_identity: true,
+            hostname: Some(DEFAULT_HOSTNAME.into()),
+            dummy_proc: Some(DummyProc {
+                cpus: 2,
+                machine_id: Some("0123456789abcdef0123456789abcdef".into()),
+                in_place: true,
+                ..DummyProc::default()
+            }),
+            ..container()
+        };
+        check("private_identity", &container);
+    }
+
+    #[test]
+    fn options() {
+        let container = Container {
+            keep_env: true,
+            base_image: Some("/images/base".into()),
+            hostname: Some("box".into()),
+            hosts: vec![Host {
+                name: "db".into(),
+                ip: "10.0.2.5".parse().unwrap(),
+            }],
+            dns: vec!["1.1.1.1".parse().unwrap()],
+            unshare_user: Some((1000, 1000)),
+            options: vec![
+                Options::SetEnv(SetEnv {
+                    key: "LANG".into(),
+                    value: "C.UTF-8".into(),
+                }),
+                Options::UnsetEnv("PATH".into()),
+                Options::Bind(Bind {
+                    src: "/srv/data".into(),
+                    dest: "/data".into(),
+                }),
+                Options::DevBind(Bind {
+                    src: "/dev/null".into(),
+                    dest: "/etc/subuid".into(),
+                }),
+                Options::RoBind(Bind {
+                    src: "/srv/ro data".into(),
+                    dest: "/ro".into(),
+                }),
+                Options::Symlink(Bind {
+                    src: "/data".into(),
+                    dest: "/link".into(),
+                }),
+                Options::Dir(Mount {
+                    path: "/work".into(),
+                }),
+            ],
+            dummy_proc: Some(DummyProc {
+                cpus: 2,
+                files: vec![("uptime".into(), b"5.00 5.00\n".to_vec())],
+                machine_id: None,
+                in_place: true,
+            }),
+            init: true,
+            command_before_unshare_user: vec!["/usr/bin/setup".into()],
+            ..container()
+        };
+        check("options", &container);
+    }
+
+    #[test]
+    fn missing_etc_file() {
+        let container = Container {
+            dns: vec!["1.1.1.1".parse().unwrap()],
+            ..container()
+        };
+        let error = plan(
+            &container,
+            &env(),
+            &entries(),
+            &Vec::new(),
+            &Vec::new(),
+            Path::new("/usr/local/bin/keg"),
+        )
+        .unwrap_err();
+        assert_eq!(error, "Cannot find /etc/resolv.conf in the base image");
+    }
+
+    #[test]
+    fn optional_etc_file() {
+        let container = Container {
+            hostname: Some("box".into()),
+            ..container()
+        };
+        let etc = vec![("hosts".into(), None)];
+        let plan = plan(
+            &container,
+            &env(),
+            &entries(),
+            &etc,
+            &Vec::new(),
+            Path::new("/usr/local/bin/keg"),
+        )
+        .unwrap();
+        let mounting = &plan.stages[plan.stages.len() - 1];
+        assert_eq!(mounting.stage, Stage::Mounting);
+        // Without /etc/hostname in the base image, only /etc/hosts is written.
+        assert_eq!(mounting.actions.len(), 1);
+        assert!(mounting.actions[0].starts_with("Write /container_etc_hosts "));
+    }
+
+    #[test]
+    fn next_stages() {
+        let stages = |container: &Container| {
+            let mut stages = vec![Stage::Isolation(0)];
+            let mut container = container.clone();
+            while stages[stages.len() - 1] != Stage::Exec {
+                let stage = &stages[stages.len() - 1];
+                let next = next_stage(stage, &container);
+                container = next_container(stage, &container);
+                stages.push(next);
+            }
+            stages
+        };
+        let full: Vec<_> = (0..=6)
+            .map(Stage::Isolation)
+            .chain([Stage::Mounting, Stage::Exec])
+            .collect();
+        let share_net = Container {
+            share_net: true,
+            ..container()
+        };
+        let net_log = Container {
+            share_net: true,
+            net_log_fd: Some(3),
+            ..container()
+        };
+        assert_eq!(
+            stages(&container()),
+            [
+                Stage::Isolation(0),
+                Stage::Isolation(6),
+                Stage::Mounting,
+                Stage::Exec
+            ]
+        );
+        assert_eq!(stages(&share_net), full);
+        assert_eq!(stages(&net_log), full);
+    }
+}
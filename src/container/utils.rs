@@ -1,19 +1,52 @@
-use libc::c_int;
+use super::TimeOffsets;
+use crate::filesystem;
+use crate::msg_retf;
+use libc::{c_int, unshare};
 use std::ffi::OsString;
+use std::fs;
 use std::io;
 use std::path::Path;
 
 pub const CLONE_NEWTIME: c_int = 0x80; // Not in `libc` crate yet
 
-pub fn ro_bind_subentries_keep_symlinks<S, D>(src: S, dest: D) -> io::Result<Vec<OsString>>
-where
-    S: AsRef<Path>,
-    D: AsRef<Path>,
-{
-    let src: &Path = src.as_ref();
-    let dest: &Path = dest.as_ref();
-    let mut files: Vec<(OsString, Option<OsString>)> = Vec::new();
-    for entry in src.read_dir()? {
+/// Returns the contents of `/proc/self/timens_offsets` setting `offsets`.
+pub fn timens_offsets(offsets: &TimeOffsets) -> String {
+    let line = |clock: &str, offset: i64| {
+        format!(
+            "{clock} {} {}\n",
+            offset.div_euclid(1_000_000_000),
+            offset.rem_euclid(1_000_000_000)
+        )
+    };
+    line("monotonic", offsets.monotonic) + &line("boottime", offsets.boottime)
+}
+
+/// Unshares the time namespace, which children of this process enter, and sets `offsets` of its
+/// clocks. This requires `cap_sys_time` if `offsets` are given.
+#[must_use]
+pub fn unshare_time(offsets: Option<&TimeOffsets>) -> bool {
+    if unsafe { unshare(CLONE_NEWTIME) } != 0 {
+        msg_retf!(
+            "Cannot unshare the time namespace: {}",
+            io::Error::last_os_error()
+        );
+    }
+    if let Some(offsets) = offsets {
+        if let Err(e) = fs::write("/proc/self/timens_offsets", timens_offsets(offsets)) {
+            // ERANGE if a clock would be negative.
+            msg_retf!("Cannot set the clock offsets: {}", e);
+        }
+    }
+    true
+}
+
+/// Entries of a directory as (file name, symlink target).
+pub type Entries = Vec<(OsString, Option<OsString>)>;
+
+/// Returns the entries of `dir`, sorted by file name.
+pub fn read_entries<P: AsRef<Path>>(dir: P) -> io::Result<Entries> {
+    let mut files: Entries = Vec::new();
+    for entry in dir.as_ref().read_dir()? {
         let entry = entry?;
         if !entry.file_type()?.is_symlink() {
             files.push((entry.file_name(), None));
@@ -22,21 +55,54 @@ where
         }
     }
     files.sort_unstable();
+    Ok(files)
+}
 
+/// Returns the entries of `/` that are made available when there is no base image.
+pub fn filesystem_entries() -> io::Result<Entries> {
+    let mut files: Entries = Vec::new();
+    filesystem::iterate(false, |file_name, symlink| {
+        files.push((file_name.into(), symlink.map(|x| x.into())));
+    })?;
+    Ok(files)
+}
+
+/// Returns bwrap arguments binding `entries` of `src` read-only to `dest`, keeping symlinks.
+pub fn ro_bind_entries(src: &Path, entries: &Entries, dest: &Path) -> Vec<OsString> {
     let mut result = Vec::new();
-    for (file_name, symlink) in files {
+    for (file_name, symlink) in entries {
         match symlink {
             None => {
                 result.push("--ro-bind".into());
-                result.push(src.join(&file_name).into());
-                result.push(dest.join(&file_name).into());
+                result.push(src.join(file_name).into());
+                result.push(dest.join(file_name).into());
             }
             Some(symlink) => {
                 result.push("--symlink".into());
-                result.push(symlink.into());
-                result.push(dest.join(&file_name).into());
+                result.push(symlink.clone());
+                result.push(dest.join(file_name).into());
             }
         }
     }
-    Ok(result)
+    result
+}
+
+pub fn ro_bind_subentries_keep_symlinks<S, D>(src: S, dest: D) -> io::Result<Vec<OsString>>
+where
+    S: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let entries = read_entries(src.as_ref())?;
+    Ok(ro_bind_entries(src.as_ref(), &entries, dest.as_ref()))
+}
+
+pub fn ro_bind_filesystem<D>(dest: D) -> io::Result<Vec<OsString>>
+where
+    D: AsRef<Path>,
+{
+    Ok(ro_bind_entries(
+        Path::new("/"),
+        &filesystem_entries()?,
+        dest.as_ref(),
+    ))
 }
@@ -0,0 +1,76 @@
+//! Contents of the dummy `/proc/loadavg`, `/proc/stat` and `/proc/uptime`, which hide the load,
+//! CPU time and uptime of the host from the container. With `--private-identity`, the dummy
+//! `/proc/cpuinfo`, `/etc/machine-id` and `/var/lib/dbus/machine-id` also hide the CPU model and
+//! the machine.
+
+use crate::container::DummyProc;
+use std::path::{Path, PathBuf};
+
+/// Names of the files that can be given with `--dummy-proc-file`.
+pub const FILE_NAMES: [&str; 3] = ["loadavg", "stat", "uptime"];
+
+/// Returns the names of the dummy files.
+pub fn file_names(dummy_proc: &DummyProc) -> Vec<&'static str> {
+    let mut result = FILE_NAMES.to_vec();
+    if dummy_proc.machine_id.is_some() {
+        result.extend(["cpuinfo", "machine-id"]);
+    }
+    result
+}
+
+/// The copy of the machine ID read by D-Bus, which some distributions keep as a separate file.
+pub const DBUS_MACHINE_ID: &str = "/var/lib/dbus/machine-id";
+
+/// Returns the paths the dummy file `file_name` replaces in the container.
+pub fn dests(file_name: &str) -> Vec<PathBuf> {
+    match file_name {
+        "machine-id" => vec![Path::new("/etc").join(file_name), DBUS_MACHINE_ID.into()],
+        _ => vec![Path::new("/proc").join(file_name)],
+    }
+}
+
+fn cpuinfo(cpus: u32) -> Vec<u8> {
+    let mut result = String::new();
+    for cpu in 0..cpus {
+        result.push_str(&format!(
+            "processor\t: {cpu}\nmodel name\t: Virtual CPU\n\n"
+        ));
+    }
+    result.into_bytes()
+}
+
+fn stat(cpus: u32) -> Vec<u8> {
+    let mut result = String::from("cpu  0 0 0 0 0 0 0 0 0 0\n");
+    for cpu in 0..cpus {
+        result.push_str(&format!("cpu{cpu} 0 0 0 0 0 0 0 0 0 0\n"));
+    }
+    result.push_str(
+        "intr 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0
+ctxt 0
+btime 100
+processes 100
+procs_running 1
+procs_blocked 0
+softirq 0 0 0 0 0 0 0 0 0 0 0
+",
+    );
+    result.into_bytes()
+}
+
+/// Returns the contents of the dummy `/proc/<file_name>`.
+pub fn contents(file_name: &str, dummy_proc: &DummyProc) -> Vec<u8> {
+    // The last `--dummy-proc-file` wins.
+    if let Some((_, contents)) = dummy_proc.files.iter().rev().find(|(x, _)| x == file_name) {
+        return contents.clone();
+    }
+    match file_name {
+        "loadavg" => b"1.00 1.00 1.00 1/100 1\n".to_vec(),
+        "stat" => stat(dummy_proc.cpus),
+        "cpuinfo" => cpuinfo(dummy_proc.cpus),
+        "machine-id" => {
+            let machine_id = dummy_proc.machine_id.as_deref().unwrap_or_default();
+            format!("{machine_id}\n").into_bytes()
+        }
+        _ => b"100.00 100.00\n".to_vec(),
+    }
+}
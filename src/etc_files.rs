@@ -0,0 +1,56 @@
+use crate::container::Host;
+use std::ffi::OsStr;
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+
+pub fn hostname(hostname: &OsStr) -> Vec<u8> {
+    let mut result = hostname.as_bytes().to_vec();
+    result.push(b'\n');
+    result
+}
+
+pub fn hosts(hostname: &OsStr, hosts: &[Host]) -> Vec<u8> {
+    let mut result = Vec::new();
+    result.extend_from_slice(&b"127.0.0.1\tlocalhost\n"[..]);
+    result.extend_from_slice(&b"::1\tlocalhost ip6-localhost ip6-loopback\n"[..]);
+    result.extend_from_slice(&b"127.0.1.1\t"[..]);
+    result.extend_from_slice(hostname.as_bytes());
+    result.push(b'\n');
+    for Host { name, ip } in hosts {
+        result.extend_from_slice(ip.to_string().as_bytes());
+        result.push(b'\t');
+        result.extend_from_slice(name.as_bytes());
+        result.push(b'\n');
+    }
+    result
+}
+
+pub fn resolv_conf(dns: &[IpAddr]) -> Vec<u8> {
+    let mut result = Vec::new();
+    for ip in dns {
+        result.extend_from_slice(&b"nameserver "[..]);
+        result.extend_from_slice(ip.to_string().as_bytes());
+        result.push(b'\n');
+    }
+    result
+}
+
+pub fn timezone() -> Vec<u8> {
+    b"Etc/UTC\n".to_vec()
+}
+
+/// Returns a TZif file for UTC, without transitions.
+pub fn utc_localtime() -> Vec<u8> {
+    let mut result = Vec::new();
+    result.extend_from_slice(b"TZif");
+    // Version 1, and 15 reserved bytes.
+    result.extend_from_slice(&[0; 16]);
+    // isutcnt, isstdcnt, leapcnt, timecnt, typecnt and charcnt.
+    for count in [0u32, 0, 0, 0, 1, 4] {
+        result.extend_from_slice(&count.to_be_bytes());
+    }
+    // The only local time type: UT offset 0, not DST, designation "UTC".
+    result.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    result.extend_from_slice(b"UTC\0");
+    result
+}
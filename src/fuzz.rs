@@ -0,0 +1,152 @@
+//! Entry points of the fuzz targets in `fuzz/`, which panic if keg mishandles their input.
+
+use crate::container::{is_valid_env_var, Container, ContainerRunner, Stage};
+use crate::ipc::{self, Message};
+use crate::overlayfs;
+use crate::run::utils::mnt_bind_dest;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path};
+
+/// `data` is the lower directories, the upper directory and the work directory, separated by
+/// 0xff.
+pub fn overlayfs_options(data: &[u8]) {
+    let dirs: Vec<&OsStr> = data.split(|b| b == &0xff).map(OsStr::from_bytes).collect();
+    if let [lowerdirs @ .., upperdir, workdir] = &dirs[..] {
+        overlayfs::check_round_trip(lowerdirs, upperdir, workdir);
+    }
+}
+
+/// A valid bind destination is mounted directly in `/mnt`.
+pub fn bind_dest(data: &[u8]) {
+    let dest = OsStr::from_bytes(data);
+    if let Ok(mnt_dest) = mnt_bind_dest(dest) {
+        let components: Vec<_> = Path::new(&mnt_dest).components().collect();
+        assert_eq!(
+            components,
+            [
+                Component::RootDir,
+                Component::Normal(OsStr::new("mnt")),
+                Component::Normal(dest)
+            ]
+        );
+    }
+}
+
+/// `data` is environment variables separated by 0xff, each a key and a value separated by the
+/// first 0xfe. They are sent to the next stage in a runner, as the environment of the command, and
+/// must arrive unchanged.
+pub fn runner_env(data: &[u8]) {
+    let env: Vec<(OsString, OsString)> = data
+        .split(|b| b == &0xff)
+        .map(|var| {
+            let (key, value) = match var.iter().position(|b| b == &0xfe) {
+                Some(i) => (&var[..i], &var[i + 1..]),
+                None => (var, &b""[..]),
+            };
+            (
+                OsString::from_vec(key.to_vec()),
+                OsString::from_vec(value.to_vec()),
+            )
+        })
+        .collect();
+    let message = Message::Runner(Box::new(ContainerRunner {
+        stage: Stage::Exec,
+        container: Container::default(),
+        env,
+    }));
+    let mut buf = Vec::new();
+    ipc::write_message(&mut buf, &message).unwrap();
+    let read_back = ipc::read_message(&mut &buf[..]).unwrap();
+    assert_eq!(read_back, message);
+}
+
+/// `data` is a key and a value separated by the first 0xfe. A valid environment variable is read
+/// back from `key=value` unchanged, and an invalid one has an empty key, '=' or NUL in the key, or
+/// NUL in the value.
+pub fn env_var(data: &[u8]) {
+    let (key, value) = match data.iter().position(|b| b == &0xfe) {
+        Some(i) => (&data[..i], &data[i + 1..]),
+        None => (data, &b""[..]),
+    };
+    if is_valid_env_var(OsStr::from_bytes(key), OsStr::from_bytes(value)) {
+        let var = [key, b"=", value].concat();
+        let i = var.iter().position(|b| b == &b'=').unwrap();
+        assert_eq!((&var[..i], &var[i + 1..]), (key, value));
+        assert!(!var.contains(&0));
+    } else {
+        assert!(key.is_empty() || key.contains(&b'=') || key.contains(&0) || value.contains(&0));
+    }
+}
+
+/// `data` is a frame sent by another stage. A message that is read is written back unchanged.
+pub fn read_message(data: &[u8]) {
+    let message = match ipc::read_message(&mut &data[..]) {
+        Ok(message) => message,
+        Err(_) => return,
+    };
+    let mut buf = Vec::new();
+    ipc::write_message(&mut buf, &message).unwrap();
+    let read_back: Message = ipc::read_message(&mut &buf[..]).unwrap();
+    assert_eq!(read_back, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Inputs with many bytes that are special to the fuzz targets.
+    fn input() -> impl Strategy<Value = Vec<u8>> {
+        proptest::collection::vec(
+            prop_oneof![
+                Just(b'\\'),
+                Just(b','),
+                Just(b':'),
+                Just(b'='),
+                Just(b'/'),
+                Just(b'.'),
+                Just(b'\0'),
+                Just(0xfe),
+                Just(0xff),
+                any::<u8>(),
+            ],
+            0..32,
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn overlayfs_options_round_trip(data in input()) {
+            overlayfs_options(&data);
+        }
+
+        #[test]
+        fn bind_dest_in_mnt(data in input()) {
+            bind_dest(&data);
+        }
+
+        #[test]
+        fn runner_env_round_trip(data in input()) {
+            runner_env(&data);
+        }
+
+        #[test]
+        fn env_var_round_trip(data in input()) {
+            env_var(&data);
+        }
+
+        #[test]
+        fn read_message_round_trip(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+            read_message(&data);
+        }
+
+        #[test]
+        fn read_framed_message_round_trip(
+            payload in proptest::collection::vec(any::<u8>(), 0..256)
+        ) {
+            let frame = [&(payload.len() as u64).to_le_bytes()[..], &payload].concat();
+            read_message(&frame);
+        }
+    }
+}
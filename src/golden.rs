@@ -0,0 +1,25 @@
+//! Golden files for unit tests, in `tests/golden`. Run the tests with `KEG_UPDATE_GOLDEN=1` to
+//! write the actual outputs instead, and review the diff.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Checks `actual` is the contents of the golden file `name`.
+pub fn check(name: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name);
+    if env::var_os("KEG_UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected =
+        fs::read_to_string(&path).unwrap_or_else(|e| panic!("Cannot read {}: {e}", path.display()));
+    assert!(
+        actual == expected,
+        "{} differs, run with KEG_UPDATE_GOLDEN=1 to update it:\n{actual}",
+        path.display()
+    );
+}
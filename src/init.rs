@@ -0,0 +1,53 @@
+//! The process waiting for the command in the exec stage, which spawns the command instead of
+//! becoming it, so that the signal killing it is known. It forwards signals to the command like
+//! the other stages, and exits with the exit status of the command.
+//!
+//! With `--init`, it also reaps the orphaned processes of the container as a child subreaper.
+//! Processes left when the command exits are killed by keg with the rest of the container.
+
+use crate::teardown::{block, forward, FORWARDED};
+use crate::{msg_and, some_or, verbose};
+use libc::{c_int, pid_t, prctl, waitpid, PR_SET_CHILD_SUBREAPER, SIGCHLD, WNOHANG};
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus};
+
+/// Runs `command`, as a child subreaper if `reap_orphans`. Returns `Err` if `command` cannot be
+/// spawned, to be reported like a failed `execv`, and `Ok(None)` if waiting for it failed.
+pub fn run(command: &mut Command, reap_orphans: bool) -> Result<Option<ExitStatus>, io::Error> {
+    if reap_orphans && unsafe { prctl(PR_SET_CHILD_SUBREAPER, 1) } != 0 {
+        msg_and!(
+            "Cannot become child subreaper: {}",
+            io::Error::last_os_error();
+            return Ok(None)
+        );
+    }
+    let mut signals = FORWARDED.to_vec();
+    signals.push(SIGCHLD);
+    // Blocked before spawning, so that SIGCHLD is received even if the command exits right away.
+    let signals = some_or!(block(&signals), return Ok(None));
+    let pid = command.spawn()?.id() as pid_t;
+
+    loop {
+        let info = match signals.read() {
+            Some(info) => info,
+            None => msg_and!("Cannot read signal: {}", io::Error::last_os_error(); return Ok(None)),
+        };
+        if info.ssi_signo as c_int != SIGCHLD {
+            forward(&info, pid);
+            continue;
+        }
+        // SIGCHLD is not queued, so one signal can stand for several exited children.
+        loop {
+            let mut status = 0;
+            let reaped = unsafe { waitpid(-1, &mut status, WNOHANG) };
+            if reaped <= 0 {
+                break;
+            }
+            if reaped == pid {
+                return Ok(Some(ExitStatus::from_raw(status)));
+            }
+            verbose!("Reaped pid {}", reaped);
+        }
+    }
+}
@@ -0,0 +1,533 @@
+//! The protocol between a stage and the next one, spoken over the socket pair passed as
+//! `/keg-bin --inner <fd>`.
+//!
+//! Both sides first send a header of `MAGIC` and `VERSION`, so a `keg-bin` speaking another
+//! version is rejected instead of misreading the container. Every message after the header is a
+//! frame: a little-endian `u64` length followed by a bincode encoded `Message`.
+//!
+//! The outer stage sends `Message::Runner`, the inner stage answers `Message::Response`, and the
+//! outer stage sends `Message::Proceed` once the inner stage may manage its own cgroup. The outer
+//! stage takes the pid of the inner stage from the credentials the kernel attaches to the
+//! response, since the pid the inner stage sees may be in another pid namespace. From then
+//! on, errors of the inner stage are sent back as `Message::Error` and printed by the outermost
+//! process with the stage they happened in, and the exec stage sends `Message::Signaled` if the
+//! command was killed by a signal. Each stage relays both to its outer stage once the inner stage
+//! exits.
+
+use crate::container::{ContainerRunner, ContainerRunnerResponse, Stage};
+use libc::{
+    c_int, c_void, getsockopt, getuid, iovec, msghdr, pid_t, recvmsg, setsockopt, socklen_t, ucred,
+    CMSG_DATA, CMSG_FIRSTHDR, CMSG_NXTHDR, MSG_PEEK, SCM_CREDENTIALS, SOL_SOCKET, SO_PASSCRED,
+    SO_PEERCRED,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::sync::Mutex;
+
+pub const MAGIC: [u8; 4] = *b"KEG\0";
+/// Bump when `Message` or anything it contains changes.
+pub const VERSION: u32 = 2;
+/// Frames larger than this are rejected before allocating.
+pub const MAX_FRAME_LEN: u64 = 1 << 30;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum Message {
+    Runner(Box<ContainerRunner>),
+    Response(ContainerRunnerResponse),
+    Proceed,
+    Error { stage: Stage, message: String },
+    Signaled { signal: c_int },
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Closed,
+    BadMagic([u8; 4]),
+    BadVersion(u32),
+    TooLarge(u64),
+    Malformed,
+    Unexpected(&'static str),
+    BadPeer,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Error::Closed
+        } else {
+            Error::Io(e)
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Closed => write!(f, "Connection closed by the other stage"),
+            Error::BadMagic(magic) => write!(f, "Not a keg stage (magic {magic:?})"),
+            Error::BadVersion(version) if *version < VERSION => write!(
+                f,
+                "The other stage runs a stale keg-bin (protocol version {version}, this one \
+                 speaks {VERSION})"
+            ),
+            Error::BadVersion(version) => write!(
+                f,
+                "This stage runs a stale keg-bin (protocol version {VERSION}, the other stage \
+                 speaks {version})"
+            ),
+            Error::TooLarge(len) => write!(f, "Frame of {len} bytes is too large"),
+            Error::Malformed => write!(f, "Malformed frame"),
+            Error::Unexpected(expected) => write!(f, "Unexpected message, expected {expected}"),
+            Error::BadPeer => write!(f, "Peer runs as another user"),
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stage::Isolation(stage) => write!(f, "Isolation stage {stage}"),
+            Stage::Mounting => write!(f, "Mounting stage"),
+            Stage::Exec => write!(f, "Exec stage"),
+        }
+    }
+}
+
+pub fn write_header<W: Write>(w: &mut W) -> Result<(), Error> {
+    let mut header = [0u8; 8];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..].copy_from_slice(&VERSION.to_le_bytes());
+    w.write_all(&header)?;
+    Ok(())
+}
+
+pub fn read_header<R: Read>(r: &mut R) -> Result<(), Error> {
+    let mut header = [0u8; 8];
+    r.read_exact(&mut header)?;
+    let magic: [u8; 4] = header[..4].try_into().unwrap();
+    if magic != MAGIC {
+        return Err(Error::BadMagic(magic));
+    }
+    let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+    if version != VERSION {
+        return Err(Error::BadVersion(version));
+    }
+    Ok(())
+}
+
+pub fn write_message<W: Write>(w: &mut W, message: &Message) -> Result<(), Error> {
+    let payload = bincode::serialize(message).map_err(|_| Error::Malformed)?;
+    let mut frame = Vec::with_capacity(8 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    w.write_all(&frame)?;
+    Ok(())
+}
+
+pub fn read_message<R: Read>(r: &mut R) -> Result<Message, Error> {
+    let mut len = [0u8; 8];
+    r.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(Error::TooLarge(len));
+    }
+    // Only allocate what was actually sent.
+    let mut payload = Vec::new();
+    r.by_ref().take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(Error::Closed);
+    }
+    let message: Message = bincode::deserialize(&payload).map_err(|_| Error::Malformed)?;
+    Ok(message)
+}
+
+/// Checks that the other end of `stream` runs as the same user as this process.
+fn check_peer(stream: &UnixStream) -> Result<(), Error> {
+    let mut cred: ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<ucred>() as socklen_t;
+    let result = unsafe {
+        getsockopt(
+            stream.as_raw_fd(),
+            SOL_SOCKET,
+            SO_PEERCRED,
+            &mut cred as *mut ucred as *mut c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(Error::Io(io::Error::last_os_error()));
+    }
+    if cred.uid != unsafe { getuid() } {
+        return Err(Error::BadPeer);
+    }
+    Ok(())
+}
+
+/// Makes the kernel pass the credentials of the sender with messages received on `stream`.
+fn set_pass_cred(stream: &UnixStream) -> Result<(), Error> {
+    let enable: c_int = 1;
+    let result = unsafe {
+        setsockopt(
+            stream.as_raw_fd(),
+            SOL_SOCKET,
+            SO_PASSCRED,
+            &enable as *const c_int as *const c_void,
+            mem::size_of::<c_int>() as socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(Error::Io(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Returns the pid of the process that sent the next bytes on `stream`, in the pid namespace of
+/// this process, without reading them. `set_pass_cred` must have been called.
+fn sender_pid(stream: &UnixStream) -> Result<pid_t, Error> {
+    let mut byte = [0u8; 1];
+    let mut iov = iovec {
+        iov_base: byte.as_mut_ptr() as *mut c_void,
+        iov_len: byte.len(),
+    };
+    // Aligned for cmsghdr, and large enough for one SCM_CREDENTIALS.
+    let mut control = [0u64; 8];
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let len = unsafe { recvmsg(stream.as_raw_fd(), &mut msg, MSG_PEEK) };
+    if len < 0 {
+        return Err(Error::Io(io::Error::last_os_error()));
+    }
+    if len == 0 {
+        return Err(Error::Closed);
+    }
+    let mut cmsg = unsafe { CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == SOL_SOCKET && header.cmsg_type == SCM_CREDENTIALS {
+            let cred: ucred = unsafe { ptr::read_unaligned(CMSG_DATA(cmsg) as *const ucred) };
+            return Ok(cred.pid);
+        }
+        cmsg = unsafe { CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Err(Error::BadPeer)
+}
+
+/// Outer side: sends `runner` to the new stage and returns its response, with the pid of the new
+/// stage in the pid namespace of this process. An error message sent by the new stage instead is
+/// reported and results in `None`.
+pub fn start_stage(
+    stream: &mut UnixStream,
+    runner: &ContainerRunner,
+) -> Result<Option<ContainerRunnerResponse>, Error> {
+    set_pass_cred(stream)?;
+    write_header(stream)?;
+    write_message(stream, &Message::Runner(Box::new(runner.clone())))?;
+    read_header(stream)?;
+    let pid = sender_pid(stream)?;
+    match read_message(stream)? {
+        Message::Response(mut response) => {
+            response.pid = pid;
+            Ok(Some(response))
+        }
+        Message::Error { stage, message } => {
+            report_from(stage, message);
+            Ok(None)
+        }
+        _ => Err(Error::Unexpected("response")),
+    }
+}
+
+/// Outer side: lets the new stage continue after it has been moved to its cgroup.
+pub fn proceed(stream: &mut UnixStream) -> Result<(), Error> {
+    write_message(stream, &Message::Proceed)
+}
+
+/// Outer side: reports the errors and the signal sent by a stage that has exited.
+pub fn relay_reports(stream: &mut UnixStream) {
+    // Nothing else has the other end after the stage exited, but never block on it.
+    if stream.set_nonblocking(true).is_err() {
+        return;
+    }
+    loop {
+        match read_message(stream) {
+            Ok(Message::Error { stage, message }) => report_from(stage, message),
+            Ok(Message::Signaled { signal }) => report_signal(signal),
+            _ => break,
+        }
+    }
+}
+
+/// Inner side: completes the handshake and returns the runner for this stage.
+pub fn accept_stage(stream: &mut UnixStream) -> Result<ContainerRunner, Error> {
+    check_peer(stream)?;
+    read_header(stream)?;
+    write_header(stream)?;
+    match read_message(stream)? {
+        Message::Runner(runner) => Ok(*runner),
+        _ => Err(Error::Unexpected("runner")),
+    }
+}
+
+/// Inner side: sends our pid and waits until the outer stage lets us proceed.
+pub fn respond(stream: &mut UnixStream, response: ContainerRunnerResponse) -> Result<(), Error> {
+    write_message(stream, &Message::Response(response))?;
+    match read_message(stream)? {
+        Message::Proceed => Ok(()),
+        _ => Err(Error::Unexpected("proceed")),
+    }
+}
+
+struct Reporter {
+    /// The outer stage, if this process is an inner stage.
+    stream: Option<UnixStream>,
+    /// The stage this process runs, once it runs one.
+    stage: Option<Stage>,
+    /// The first error reported to the outermost process, and the stage it happened in.
+    first_error: Option<(Option<Stage>, String)>,
+    /// The signal that killed the command, as reported to the outermost process.
+    signal: Option<c_int>,
+}
+
+static REPORTER: Mutex<Reporter> = Mutex::new(Reporter {
+    stream: None,
+    stage: None,
+    first_error: None,
+    signal: None,
+});
+
+/// Inner side: sends errors of this process, which runs `stage`, to the outer stage.
+pub fn set_reporter(stream: &UnixStream, stage: Stage) {
+    let mut reporter = REPORTER.lock().unwrap();
+    if let Ok(stream) = stream.try_clone() {
+        reporter.stream = Some(stream);
+    }
+    reporter.stage = Some(stage);
+}
+
+/// Outermost side: records `stage` as the stage of errors reported from now on.
+pub fn set_stage(stage: Stage) {
+    REPORTER.lock().unwrap().stage = Some(stage);
+}
+
+/// Returns the stage this process runs, if any.
+pub fn stage() -> Option<Stage> {
+    REPORTER.lock().unwrap().stage.clone()
+}
+
+/// Returns the first error reported to the outermost process, and the stage it happened in.
+pub fn first_error() -> Option<(Option<Stage>, String)> {
+    REPORTER.lock().unwrap().first_error.clone()
+}
+
+/// Returns the signal that killed the command, as reported to the outermost process.
+pub fn signal() -> Option<c_int> {
+    REPORTER.lock().unwrap().signal
+}
+
+/// Reports that the command was killed by `signal`. Inner stages send it to the outer stage, the
+/// outermost process records it.
+pub fn report_signal(signal: c_int) {
+    let mut reporter = REPORTER.lock().unwrap();
+    if let Some(stream) = reporter.stream.as_mut() {
+        if write_message(stream, &Message::Signaled { signal }).is_ok() {
+            return;
+        }
+    }
+    reporter.signal = Some(signal);
+}
+
+/// Reports an error of this process. Inner stages send it to the outer stage, the outermost
+/// process prints it.
+pub fn report(message: String) {
+    let mut reporter = REPORTER.lock().unwrap();
+    let stage = reporter.stage.clone();
+    if reporter.stream.is_none() {
+        eprintln!("{message}");
+        reporter.first_error.get_or_insert((stage, message));
+        return;
+    }
+    drop(reporter);
+    report_from(stage.unwrap_or(Stage::Isolation(0)), message);
+}
+
+/// Reports an error of `stage`, keeping the stage it happened in.
+fn report_from(stage: Stage, message: String) {
+    let mut reporter = REPORTER.lock().unwrap();
+    if let Some(stream) = reporter.stream.as_mut() {
+        let error = Message::Error {
+            stage: stage.clone(),
+            message: message.clone(),
+        };
+        if write_message(stream, &error).is_ok() {
+            return;
+        }
+    }
+    eprintln!("{stage}: {message}");
+    reporter.first_error.get_or_insert((Some(stage), message));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::Container;
+
+    fn runner() -> ContainerRunner {
+        ContainerRunner {
+            stage: Stage::Isolation(3),
+            container: Container {
+                command: vec!["/bin/true".into()],
+                net_nft_rules: b"table inet t {}\n".to_vec(),
+                ..Container::default()
+            },
+            env: vec![("HOME".into(), "/root".into())],
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut buf = Vec::new();
+        write_header(&mut buf).unwrap();
+        assert_eq!(buf.len(), 8);
+        read_header(&mut &buf[..]).unwrap();
+    }
+
+    #[test]
+    fn bad_magic() {
+        let buf = b"\0\0\0\0\x01\0\0\0";
+        assert!(matches!(
+            read_header(&mut &buf[..]),
+            Err(Error::BadMagic([0, 0, 0, 0]))
+        ));
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            read_header(&mut &buf[..]),
+            Err(Error::BadVersion(v)) if v == VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let messages = [
+            Message::Runner(Box::new(runner())),
+            Message::Response(ContainerRunnerResponse { pid: 42 }),
+            Message::Proceed,
+            Message::Error {
+                stage: Stage::Mounting,
+                message: "Failed".into(),
+            },
+            Message::Signaled { signal: 9 },
+        ];
+        let mut buf = Vec::new();
+        for message in &messages {
+            write_message(&mut buf, message).unwrap();
+        }
+        let mut r = &buf[..];
+        for message in &messages {
+            assert_eq!(&read_message(&mut r).unwrap(), message);
+        }
+        assert!(matches!(read_message(&mut r), Err(Error::Closed)));
+    }
+
+    #[test]
+    fn runner_debug_omits_values() {
+        let debug = format!("{:?}", runner());
+        assert!(debug.contains("\"HOME\""));
+        assert!(!debug.contains("/root") && !debug.contains("table"));
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &Message::Runner(Box::new(runner()))).unwrap();
+        for len in [0, 4, 8, buf.len() - 1] {
+            assert!(matches!(read_message(&mut &buf[..len]), Err(Error::Closed)));
+        }
+    }
+
+    #[test]
+    fn too_large_frame() {
+        let buf = (MAX_FRAME_LEN + 1).to_le_bytes();
+        assert!(matches!(
+            read_message(&mut &buf[..]),
+            Err(Error::TooLarge(len)) if len == MAX_FRAME_LEN + 1
+        ));
+    }
+
+    #[test]
+    fn malformed_frame() {
+        let mut buf = 4u64.to_le_bytes().to_vec();
+        buf.extend_from_slice(&[0xff; 4]);
+        assert!(matches!(read_message(&mut &buf[..]), Err(Error::Malformed)));
+    }
+
+    #[test]
+    fn handshake() {
+        let (mut outer, mut inner) = UnixStream::pair().unwrap();
+        let thread = std::thread::spawn(move || {
+            let runner = accept_stage(&mut inner).unwrap();
+            respond(&mut inner, ContainerRunnerResponse { pid: 7 }).unwrap();
+            runner
+        });
+        let response = start_stage(&mut outer, &runner()).unwrap().unwrap();
+        // The pid is taken from the credentials of the response.
+        let pid = std::process::id() as pid_t;
+        assert_eq!(response, ContainerRunnerResponse { pid });
+        proceed(&mut outer).unwrap();
+        assert_eq!(thread.join().unwrap(), runner());
+    }
+
+    #[test]
+    fn stale_inner_stage() {
+        let (mut outer, mut inner) = UnixStream::pair().unwrap();
+        // The header of a newer outer stage; the inner stage reads it before writing its own.
+        outer.write_all(&MAGIC).unwrap();
+        outer.write_all(&(VERSION + 1).to_le_bytes()).unwrap();
+        let error = accept_stage(&mut inner).unwrap_err();
+        assert!(matches!(error, Error::BadVersion(v) if v == VERSION + 1));
+        assert!(error
+            .to_string()
+            .starts_with("This stage runs a stale keg-bin"));
+        drop(inner);
+        assert!(matches!(read_header(&mut outer), Err(Error::Closed)));
+    }
+
+    #[test]
+    fn stale_version_message() {
+        let error = Error::BadVersion(VERSION - 1);
+        assert!(error
+            .to_string()
+            .starts_with("The other stage runs a stale keg-bin"));
+    }
+
+    #[test]
+    fn error_instead_of_response() {
+        let (mut outer, mut inner) = UnixStream::pair().unwrap();
+        let thread = std::thread::spawn(move || {
+            read_header(&mut inner).unwrap();
+            write_header(&mut inner).unwrap();
+            read_message(&mut inner).unwrap();
+            let message = Message::Error {
+                stage: Stage::Isolation(5),
+                message: "Failed to load nft rules".into(),
+            };
+            write_message(&mut inner, &message).unwrap();
+        });
+        assert!(start_stage(&mut outer, &runner()).unwrap().is_none());
+        thread.join().unwrap();
+    }
+}
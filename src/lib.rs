@@ -2,13 +2,31 @@ mod bwrap;
 mod cgroup;
 mod container;
 mod die_with_parent;
+mod dummy_proc;
+mod etc_files;
 mod filesystem;
+#[cfg(any(test, feature = "fuzz"))]
+pub mod fuzz;
+#[cfg(test)]
+mod golden;
+mod init;
+mod ipc;
+mod json;
 mod keyring;
+mod log;
 mod masked_paths;
+mod netns;
+mod nflog;
 mod overlayfs;
+mod procfs;
+mod registry;
 mod seccomp;
 mod slirp;
 mod socket_pair;
+mod stats;
+mod status;
+mod suspend;
+mod teardown;
 mod utils;
 
 pub mod run;
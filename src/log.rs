@@ -0,0 +1,119 @@
+//! Tracing for `--verbose` and `--debug`.
+//!
+//! Every stage logs to the same destination: stderr, or the `--log-file`, whose fd is inherited
+//! by the inner stages and closed before the command is executed. Each line is
+//! `[<seconds>.<microseconds>] <stage>: <message>`, with the time since the Unix epoch.
+
+use crate::ipc;
+use crate::socket_pair::set_cloexec;
+use libc::c_int;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::process::Command;
+use std::sync::atomic::{AtomicI32, AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Stage transitions, timing, command lines and cgroup moves.
+pub const VERBOSE: u8 = 1;
+/// Everything sent between stages, in addition.
+pub const DEBUG: u8 = 2;
+
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+static FD: AtomicI32 = AtomicI32::new(-1);
+
+pub fn init(level: u8, fd: Option<c_int>) {
+    LEVEL.store(level, Ordering::Relaxed);
+    FD.store(fd.unwrap_or(-1), Ordering::Relaxed);
+}
+
+pub fn enabled(level: u8) -> bool {
+    level <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Keeps the log file from being inherited by processes started from now on.
+pub fn close_on_exec() {
+    let fd = FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        unsafe { set_cloexec(fd) };
+    }
+}
+
+/// Returns the time since the Unix epoch as `<seconds>.<microseconds>`.
+pub fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}.{:06}", now.as_secs(), now.subsec_micros())
+}
+
+pub fn write(message: &str) {
+    let stage = match ipc::stage() {
+        Some(stage) => stage.to_string(),
+        None => "keg".to_owned(),
+    };
+    let line = format!("[{}] {}: {}\n", timestamp(), stage, message);
+    let fd = FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // The fd stays open; it is shared with the other stages.
+        let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        drop(file.write_all(line.as_bytes()));
+    } else {
+        drop(io::stderr().write_all(line.as_bytes()));
+    }
+}
+
+/// Quotes `arg` for a shell if needed.
+fn quote(arg: &OsStr) -> String {
+    let arg = String::from_utf8_lossy(arg.as_bytes());
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.into_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Returns the command line of `program` and `args`, quoted for a shell.
+pub fn command_line<'a, I>(program: &OsStr, args: I) -> String
+where
+    I: IntoIterator<Item = &'a OsStr>,
+{
+    let mut line = quote(program);
+    for arg in args {
+        line.push(' ');
+        line.push_str(&quote(arg));
+    }
+    line
+}
+
+/// Logs that `command` is about to run.
+pub fn command(command: &Command) {
+    if enabled(VERBOSE) {
+        write(&format!(
+            "Running {}",
+            command_line(command.get_program(), command.get_args())
+        ));
+    }
+}
+
+#[macro_export]
+macro_rules! verbose {
+    ($fmt: expr $(, $arg: expr)*) => {{
+        if $crate::log::enabled($crate::log::VERBOSE) {
+            $crate::log::write(&format!($fmt $(, $arg)*));
+        }
+    }}
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($fmt: expr $(, $arg: expr)*) => {{
+        if $crate::log::enabled($crate::log::DEBUG) {
+            $crate::log::write(&format!($fmt $(, $arg)*));
+        }
+    }}
+}
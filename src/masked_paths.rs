@@ -82,3 +82,36 @@ pub fn podman_arg() -> &'static str {
         "/proc/zoneinfo",
     )
 }
+
+/// Files of `/etc` that identify the host, which are emptied with `--private-identity`.
+pub const PRIVATE_IDENTITY_ETC_FILES: [&str; 4] = ["adjtime", "crypttab", "fstab", "machine-info"];
+
+/// Files of `/proc` that identify the host, which are emptied with `--private-identity`.
+pub const PRIVATE_IDENTITY_PROC_FILES: [&str; 1] = ["version"];
+
+/// Returns the argument of podman masking the hardware of the host,
+/// `PRIVATE_IDENTITY_ETC_FILES` and `PRIVATE_IDENTITY_PROC_FILES`, for `--private-identity`.
+pub fn private_identity_podman_arg() -> String {
+    let mut arg = concat!(
+        "--security-opt=mask=",
+        "/sys/class/dmi",
+        ":",
+        "/sys/class/hwmon",
+        ":",
+        "/sys/class/power_supply",
+        ":",
+        "/sys/devices/virtual/dmi",
+        ":",
+        "/sys/firmware",
+    )
+    .to_owned();
+    for file_name in PRIVATE_IDENTITY_ETC_FILES {
+        arg.push_str(":/etc/");
+        arg.push_str(file_name);
+    }
+    for file_name in PRIVATE_IDENTITY_PROC_FILES {
+        arg.push_str(":/proc/");
+        arg.push_str(file_name);
+    }
+    arg
+}
@@ -0,0 +1,54 @@
+//! Joining the network namespace of another running container.
+
+use crate::procfs::{cmdline, descendants};
+use crate::{msg_retf, ok_or, some_or};
+use libc::{c_ulong, ioctl, pid_t, setns, CLONE_NEWNET, CLONE_NEWUSER};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+const NS_GET_USERNS: c_ulong = 0xb701; // Not in `libc` crate yet
+
+/// Returns the innermost keg process of the container whose outermost process is `pid`. It runs
+/// in the final network namespace of the container.
+fn innermost_keg_bin(pid: pid_t) -> Option<pid_t> {
+    descendants(pid)
+        .into_iter()
+        .filter(|(pid, _)| {
+            cmdline(*pid).is_some_and(|cmdline| cmdline.starts_with(b"/keg-bin\0--inner\0"))
+        })
+        .max_by_key(|(_, depth)| *depth)
+        .map(|(pid, _)| pid)
+}
+
+/// Joins the final network namespace of the container whose outermost process is `pid`, together
+/// with the user namespace that owns it. Must be called while single-threaded.
+#[must_use]
+pub fn join(pid: pid_t) -> bool {
+    let target = some_or!(
+        innermost_keg_bin(pid),
+        msg_retf!("The container is not running, or has not started yet")
+    );
+    let net = ok_or!(
+        File::open(format!("/proc/{target}/ns/net")),
+        msg_retf!("Cannot open network namespace")
+    );
+    let user = unsafe { ioctl(net.as_raw_fd(), NS_GET_USERNS) };
+    if user < 0 {
+        msg_retf!(
+            "Cannot get owner of network namespace: {}",
+            io::Error::last_os_error()
+        );
+    }
+    let user = unsafe { File::from_raw_fd(user) };
+    if unsafe { setns(user.as_raw_fd(), CLONE_NEWUSER) } != 0 {
+        msg_retf!("Cannot join user namespace: {}", io::Error::last_os_error());
+    }
+    if unsafe { setns(net.as_raw_fd(), CLONE_NEWNET) } != 0 {
+        msg_retf!(
+            "Cannot join network namespace: {}",
+            io::Error::last_os_error()
+        );
+    }
+    true
+}
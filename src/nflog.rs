@@ -0,0 +1,279 @@
+//! Receiving packets logged to an nflog group, e.g. by the nftables statement `log group <N>`, from
+//! the nfnetlink_log subsystem of the network namespace of this process.
+
+use crate::msg_ret;
+use libc::{
+    bind, c_int, recv, send, setsockopt, sockaddr, sockaddr_nl, socket, socklen_t, timeval,
+    AF_NETLINK, AF_UNSPEC, NETLINK_NETFILTER, NFNETLINK_V0, NFNL_SUBSYS_ULOG, NFULA_CFG_CMD,
+    NFULA_CFG_MODE, NFULA_PAYLOAD, NFULNL_CFG_CMD_BIND, NFULNL_COPY_PACKET, NFULNL_MSG_CONFIG,
+    NFULNL_MSG_PACKET, NLMSG_ERROR, NLM_F_ACK, NLM_F_REQUEST, SOCK_CLOEXEC, SOCK_RAW, SOL_SOCKET,
+    SO_RCVTIMEO,
+};
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+/// Bytes copied of each packet, enough for an IPv4 header with options or an IPv6 header, and the
+/// ports of the transport header.
+const COPY_RANGE: u32 = 64;
+
+/// Size of `nlmsghdr`, and of `nfgenmsg` that follows it in nfnetlink messages.
+const NLMSG_HDRLEN: usize = 16;
+const NFGENMSG_LEN: usize = 4;
+
+/// The destination of a TCP or UDP packet, as (address, protocol, port).
+pub type Destination = (IpAddr, &'static str, u16);
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Returns a configuration request for `group` with the attribute `attr_type`.
+fn config_message(group: u16, attr_type: c_int, payload: &[u8]) -> Vec<u8> {
+    let attr_len = 4 + payload.len();
+    let len = NLMSG_HDRLEN + NFGENMSG_LEN + align(attr_len);
+    let mut message = Vec::with_capacity(len);
+    message.extend_from_slice(&(len as u32).to_ne_bytes());
+    message
+        .extend_from_slice(&(((NFNL_SUBSYS_ULOG << 8) | NFULNL_MSG_CONFIG) as u16).to_ne_bytes());
+    message.extend_from_slice(&((NLM_F_REQUEST | NLM_F_ACK) as u16).to_ne_bytes());
+    // Sequence number and port id.
+    message.extend_from_slice(&[0; 8]);
+    message.push(AF_UNSPEC as u8);
+    message.push(NFNETLINK_V0 as u8);
+    message.extend_from_slice(&group.to_be_bytes());
+    message.extend_from_slice(&(attr_len as u16).to_ne_bytes());
+    message.extend_from_slice(&(attr_type as u16).to_ne_bytes());
+    message.extend_from_slice(payload);
+    message.resize(len, 0);
+    message
+}
+
+/// Returns the destination of the IPv4 or IPv6 packet `packet`, if it is TCP or UDP. IPv6
+/// extension headers are not followed.
+fn parse_packet(packet: &[u8]) -> Option<Destination> {
+    let (address, protocol, transport) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0xf) * 4;
+            let address: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::from(address),
+                *packet.get(9)?,
+                packet.get(header_len..)?,
+            )
+        }
+        6 => {
+            let address: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (IpAddr::from(address), *packet.get(6)?, packet.get(40..)?)
+        }
+        _ => return None,
+    };
+    let protocol = match protocol {
+        6 => "tcp",
+        17 => "udp",
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(transport.get(2..4)?.try_into().ok()?);
+    Some((address, protocol, port))
+}
+
+/// Returns the destinations of the packets in the netlink messages `buf`, or the error of an
+/// acknowledgment.
+fn parse_messages(mut buf: &[u8]) -> io::Result<Vec<Destination>> {
+    let mut result = Vec::new();
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes(buf[..4].try_into().unwrap()) as usize;
+        let message_type = c_int::from(u16::from_ne_bytes(buf[4..6].try_into().unwrap()));
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            break;
+        }
+        let message = &buf[NLMSG_HDRLEN..len];
+        if message_type == NLMSG_ERROR && message.len() >= 4 {
+            let error = i32::from_ne_bytes(message[..4].try_into().unwrap());
+            if error != 0 {
+                return Err(io::Error::from_raw_os_error(-error));
+            }
+        } else if message_type == (NFNL_SUBSYS_ULOG << 8) | NFULNL_MSG_PACKET {
+            let mut attrs = message.get(NFGENMSG_LEN..).unwrap_or_default();
+            while attrs.len() >= 4 {
+                let attr_len = usize::from(u16::from_ne_bytes(attrs[..2].try_into().unwrap()));
+                // Without NLA_F_NESTED and NLA_F_NET_BYTEORDER.
+                let attr_type =
+                    c_int::from(u16::from_ne_bytes(attrs[2..4].try_into().unwrap())) & 0x3fff;
+                if attr_len < 4 || attr_len > attrs.len() {
+                    break;
+                }
+                if attr_type == NFULA_PAYLOAD {
+                    result.extend(parse_packet(&attrs[4..attr_len]));
+                }
+                attrs = attrs.get(align(attr_len)..).unwrap_or_default();
+            }
+        }
+        buf = buf.get(align(len)..).unwrap_or_default();
+    }
+    Ok(result)
+}
+
+/// A netlink socket bound to an nflog group.
+pub struct Nflog {
+    fd: OwnedFd,
+}
+
+impl Nflog {
+    /// Binds the nflog group `group`, and makes `recv` time out after `timeout`. Requires
+    /// CAP_NET_ADMIN in the network namespace.
+    pub fn bind(group: u16, timeout: timeval) -> Option<Self> {
+        let fd = unsafe { socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_NETFILTER) };
+        if fd < 0 {
+            msg_ret!("Cannot open netlink socket: {}", io::Error::last_os_error());
+        }
+        let nflog = Nflog {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        let mut addr: sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = AF_NETLINK as u16;
+        let result = unsafe {
+            bind(
+                fd,
+                &addr as *const sockaddr_nl as *const sockaddr,
+                mem::size_of::<sockaddr_nl>() as socklen_t,
+            )
+        };
+        if result != 0 {
+            msg_ret!("Cannot bind netlink socket: {}", io::Error::last_os_error());
+        }
+
+        let mut mode = COPY_RANGE.to_be_bytes().to_vec();
+        mode.extend_from_slice(&[NFULNL_COPY_PACKET as u8, 0]);
+        for (attr_type, payload) in [
+            (NFULA_CFG_CMD, vec![NFULNL_CFG_CMD_BIND as u8]),
+            (NFULA_CFG_MODE, mode),
+        ] {
+            if let Err(e) = nflog.request(&config_message(group, attr_type, &payload)) {
+                msg_ret!("Cannot bind nflog group {}: {}", group, e);
+            }
+        }
+
+        let result = unsafe {
+            setsockopt(
+                fd,
+                SOL_SOCKET,
+                SO_RCVTIMEO,
+                &timeout as *const timeval as *const _,
+                mem::size_of::<timeval>() as socklen_t,
+            )
+        };
+        if result != 0 {
+            msg_ret!("Cannot set netlink timeout: {}", io::Error::last_os_error());
+        }
+        Some(nflog)
+    }
+
+    /// Sends `message`, and waits for its acknowledgment.
+    fn request(&self, message: &[u8]) -> io::Result<()> {
+        let fd = self.fd.as_raw_fd();
+        if unsafe { send(fd, message.as_ptr() as *const _, message.len(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = [0u8; 256];
+        let len = unsafe { recv(fd, buf.as_mut_ptr() as *mut _, buf.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        parse_messages(&buf[..len as usize]).map(drop)
+    }
+
+    /// Waits for logged packets, and returns the destinations of those that are TCP or UDP. Fails
+    /// with `WouldBlock` after the timeout, and with ENOBUFS if packets were lost because they were
+    /// not received in time.
+    pub fn recv(&self) -> io::Result<Vec<Destination>> {
+        let mut buf = vec![0u8; 65536];
+        let len = unsafe {
+            recv(
+                self.fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut _,
+                buf.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        parse_messages(&buf[..len as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPV4_TCP: [u8; 24] = [
+        0x45, 0, 0, 60, 0, 0, 0x40, 0, 64, 6, 0, 0, 10, 0, 2, 100, 1, 1, 1, 1, 0xc3, 0x50, 0x01,
+        0xbb,
+    ];
+
+    fn packet_message(payload: &[u8]) -> Vec<u8> {
+        let mut message = config_message(7, NFULA_PAYLOAD, payload);
+        message[4..6]
+            .copy_from_slice(&(((NFNL_SUBSYS_ULOG << 8) | NFULNL_MSG_PACKET) as u16).to_ne_bytes());
+        message
+    }
+
+    #[test]
+    fn packets() {
+        assert_eq!(
+            parse_packet(&IPV4_TCP),
+            Some(("1.1.1.1".parse().unwrap(), "tcp", 443))
+        );
+
+        let mut ipv6_udp = vec![0x60, 0, 0, 0, 0, 8, 17, 64];
+        ipv6_udp.extend_from_slice(&[0; 16]);
+        ipv6_udp.extend_from_slice(
+            &"2606:4700::1111"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        ipv6_udp.extend_from_slice(&[0xc3, 0x50, 0, 53]);
+        assert_eq!(
+            parse_packet(&ipv6_udp),
+            Some(("2606:4700::1111".parse().unwrap(), "udp", 53))
+        );
+
+        // ICMP, truncated and not IP.
+        let mut icmp = IPV4_TCP;
+        icmp[9] = 1;
+        assert_eq!(parse_packet(&icmp), None);
+        assert_eq!(parse_packet(&IPV4_TCP[..22]), None);
+        assert_eq!(parse_packet(&[0x10; 24]), None);
+        assert_eq!(parse_packet(&[]), None);
+    }
+
+    #[test]
+    fn messages() {
+        let mut buf = packet_message(&IPV4_TCP);
+        buf.extend(packet_message(&IPV4_TCP[..10]));
+        buf.extend(packet_message(&IPV4_TCP));
+        let destination = ("1.1.1.1".parse().unwrap(), "tcp", 443);
+        assert_eq!(
+            parse_messages(&buf).unwrap(),
+            vec![destination, destination]
+        );
+        // A truncated message is ignored.
+        assert_eq!(parse_messages(&buf[..60]).unwrap(), vec![destination]);
+    }
+
+    #[test]
+    fn acknowledgments() {
+        let mut ack = vec![36, 0, 0, 0];
+        ack.extend_from_slice(&(NLMSG_ERROR as u16).to_ne_bytes());
+        ack.extend_from_slice(&[0; 10]);
+        ack.extend_from_slice(&0i32.to_ne_bytes());
+        ack.extend_from_slice(&[0; 16]);
+        assert_eq!(parse_messages(&ack).unwrap(), vec![]);
+        ack[16..20].copy_from_slice(&(-libc::EPERM).to_ne_bytes());
+        let error = parse_messages(&ack).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EPERM));
+    }
+}
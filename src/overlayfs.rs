@@ -18,6 +18,11 @@ fn escape_options(opt: &OsStr) -> OsString {
     OsString::from_vec(ret)
 }
 
+/// fuse-overlayfs does not support escaping ':', and the nul byte ends its arguments.
+fn is_valid_dir(dir: &OsStr) -> bool {
+    !dir.as_bytes().contains(&b':') && !dir.as_bytes().contains(&b'\0')
+}
+
 pub fn get_command<'a, L>(
     lowerdirs: L,
     upperdir: &OsStr,
@@ -36,8 +41,7 @@ where
         if i > 0 {
             mount_options.push(":");
         }
-        if lower.as_bytes().contains(&b':') {
-            // fuse-overlayfs does not support escaping ':'
+        if !is_valid_dir(lower) {
             return None;
         }
         mount_options.push(escape_options(lower));
@@ -47,13 +51,13 @@ where
     }
     // Upper
     mount_options.push(",upperdir=");
-    if upperdir.as_bytes().contains(&b':') {
+    if !is_valid_dir(upperdir) {
         return None;
     }
     mount_options.push(escape_options(upperdir));
     // Work
     mount_options.push(",workdir=");
-    if workdir.as_bytes().contains(&b':') {
+    if !is_valid_dir(workdir) {
         return None;
     }
     mount_options.push(escape_options(workdir));
@@ -65,3 +69,112 @@ where
         merged.to_owned(),
     ])
 }
+
+/// Splits the `-o` argument of fuse-overlayfs like libfuse does: options are separated by ',', and
+/// a backslash escapes the next byte.
+#[cfg(any(test, feature = "fuzz"))]
+fn split_options(opt: &[u8]) -> Vec<Vec<u8>> {
+    let mut options = vec![Vec::new()];
+    let mut bytes = opt.iter();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'\\' => match bytes.next() {
+                Some(escaped) => options.last_mut().unwrap().push(*escaped),
+                None => options.last_mut().unwrap().push(*b),
+            },
+            b',' => options.push(Vec::new()),
+            _ => options.last_mut().unwrap().push(*b),
+        }
+    }
+    options
+}
+
+/// Checks that fuse-overlayfs, run with the command for `lowerdirs`, `upperdir` and `workdir`,
+/// mounts exactly those directories, or that there is no command.
+#[cfg(any(test, feature = "fuzz"))]
+pub fn check_round_trip(lowerdirs: &[&OsStr], upperdir: &OsStr, workdir: &OsStr) {
+    let command = get_command(
+        lowerdirs.iter().copied(),
+        upperdir,
+        workdir,
+        OsStr::new("/merged"),
+    );
+    let command = match command {
+        Some(command) => command,
+        None => {
+            assert!(
+                lowerdirs.is_empty()
+                    || !lowerdirs.iter().all(|x| is_valid_dir(x))
+                    || !is_valid_dir(upperdir)
+                    || !is_valid_dir(workdir)
+            );
+            return;
+        }
+    };
+    assert_eq!(command.len(), 4);
+    assert_eq!(command[3], "/merged");
+    let options = split_options(command[2].as_bytes());
+    assert_eq!(options.len(), 4);
+    assert_eq!(options[0], b"squash_to_root");
+    let lower = options[1].strip_prefix(b"lowerdir=").unwrap();
+    let lower: Vec<&[u8]> = lower.split(|b| b == &b':').collect();
+    let expected: Vec<&[u8]> = lowerdirs.iter().map(|x| x.as_bytes()).collect();
+    assert_eq!(lower, expected);
+    assert_eq!(
+        options[2],
+        [&b"upperdir="[..], upperdir.as_bytes()].concat()
+    );
+    assert_eq!(options[3], [&b"workdir="[..], workdir.as_bytes()].concat());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Paths with many bytes that are special to fuse-overlayfs.
+    fn dir() -> impl Strategy<Value = OsString> {
+        proptest::collection::vec(
+            prop_oneof![
+                Just(b'\\'),
+                Just(b','),
+                Just(b':'),
+                Just(b'='),
+                Just(b'/'),
+                Just(b'\0'),
+                any::<u8>(),
+            ],
+            0..16,
+        )
+        .prop_map(OsString::from_vec)
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(
+            escape_options(OsStr::new("a\\b,c:d")),
+            OsStr::new("a\\\\b\\,c\\:d")
+        );
+    }
+
+    #[test]
+    fn rejects_colon() {
+        let lower = [OsStr::new("/a:b")];
+        assert_eq!(
+            get_command(lower, OsStr::new("/u"), OsStr::new("/w"), OsStr::new("/m")),
+            None
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(
+            lowerdirs in proptest::collection::vec(dir(), 0..4),
+            upperdir in dir(),
+            workdir in dir(),
+        ) {
+            let lowerdirs: Vec<&OsStr> = lowerdirs.iter().map(|x| &x[..]).collect();
+            check_round_trip(&lowerdirs, &upperdir, &workdir);
+        }
+    }
+}
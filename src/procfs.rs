@@ -0,0 +1,56 @@
+//! Process information from `/proc`.
+
+use crate::{ok_or, some_or, some_or_ret};
+use libc::pid_t;
+use std::collections::HashMap;
+use std::fs;
+
+/// Returns the fields of `/proc/<pid>/stat` after the command name, starting from the state.
+fn stat_fields(pid: pid_t) -> Option<Vec<String>> {
+    let stat = ok_or!(fs::read(format!("/proc/{pid}/stat")), return None);
+    // The command name may contain spaces and parentheses.
+    let end_of_comm = some_or_ret!(stat.iter().rposition(|c| c == &b')'));
+    Some(
+        String::from_utf8_lossy(&stat[end_of_comm + 1..])
+            .split_whitespace()
+            .map(|x| x.to_owned())
+            .collect(),
+    )
+}
+
+/// Returns the start time of `pid` in clock ticks after boot, to detect pid reuse.
+pub fn start_time(pid: pid_t) -> Option<u64> {
+    // Field 22 of stat, counting from the state which is field 3.
+    let fields = some_or_ret!(stat_fields(pid));
+    fields.get(19).and_then(|x| x.parse().ok())
+}
+
+pub fn cmdline(pid: pid_t) -> Option<Vec<u8>> {
+    fs::read(format!("/proc/{pid}/cmdline")).ok()
+}
+
+/// Returns all descendants of `pid` with their depth, parents before children.
+pub fn descendants(pid: pid_t) -> Vec<(pid_t, usize)> {
+    let mut children: HashMap<pid_t, Vec<pid_t>> = HashMap::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let child: pid_t = ok_or!(entry.file_name().to_string_lossy().parse(), continue);
+            let fields = some_or!(stat_fields(child), continue);
+            // Field 4 of stat.
+            let parent: pid_t = ok_or!(some_or!(fields.get(1), continue).parse(), continue);
+            children.entry(parent).or_default().push(child);
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut queue = vec![(pid, 0)];
+    while let Some((pid, depth)) = queue.pop() {
+        if let Some(children) = children.get(&pid) {
+            for child in children {
+                result.push((*child, depth + 1));
+                queue.push((*child, depth + 1));
+            }
+        }
+    }
+    result
+}
@@ -0,0 +1,86 @@
+//! Names of running containers, kept as files in `$XDG_RUNTIME_DIR/keg`. Each file contains the
+//! pid and the start time of the outermost keg process.
+
+use crate::procfs::start_time;
+use crate::{msg_ret, ok_or, some_or, some_or_ret};
+use libc::pid_t;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+pub struct Registration {
+    path: PathBuf,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        drop(fs::remove_file(&self.path));
+    }
+}
+
+fn dir() -> Option<PathBuf> {
+    let runtime_dir = some_or!(
+        env::var_os("XDG_RUNTIME_DIR"),
+        msg_ret!("XDG_RUNTIME_DIR must be set to name containers")
+    );
+    Some(PathBuf::from(runtime_dir).join("keg"))
+}
+
+fn read(path: &Path) -> Option<pid_t> {
+    let contents = ok_or!(fs::read_to_string(path), return None);
+    let (pid, time) = some_or_ret!(contents.trim_end().split_once(' '));
+    let pid: pid_t = ok_or!(pid.parse(), return None);
+    let time: u64 = ok_or!(time.parse(), return None);
+    if start_time(pid) == Some(time) {
+        Some(pid)
+    } else {
+        None
+    }
+}
+
+/// Registers the current process as `name`. A registration left by a process that is no longer
+/// running is replaced.
+pub fn register(name: &OsStr) -> Option<Registration> {
+    let dir = some_or!(dir(), return None);
+    ok_or!(
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir),
+        msg_ret!("Failed to create directory \"{}\"", dir.display())
+    );
+    let path = dir.join(name);
+    let pid = process::id() as pid_t;
+    let time = some_or!(start_time(pid), msg_ret!("Cannot read process start time"));
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                ok_or!(
+                    file.write_all(format!("{pid} {time}\n").as_bytes()),
+                    msg_ret!("Failed to write \"{}\"", path.display())
+                );
+                return Some(Registration { path });
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if read(&path).is_some() {
+                    msg_ret!("A container named {} is running", name.to_string_lossy());
+                }
+                ok_or!(
+                    fs::remove_file(&path),
+                    msg_ret!("Failed to remove \"{}\"", path.display())
+                );
+            }
+            Err(e) => msg_ret!("Failed to create \"{}\": {}", path.display(), e),
+        }
+    }
+}
+
+/// Returns the pid of the outermost process of the running container `name`.
+pub fn lookup(name: &OsStr) -> Option<pid_t> {
+    let path = some_or!(dir(), return None).join(name);
+    Some(some_or!(
+        read(&path),
+        msg_ret!("No container named {} is running", name.to_string_lossy())
+    ))
+}
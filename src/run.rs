@@ -1,9 +1,12 @@
 //! Modules that are intended to be run as `fn main` from a binary target.
 
-mod utils;
+pub(crate) mod utils;
 
 pub mod base;
+pub mod bench;
+pub mod cleanup;
 pub mod inner;
+pub mod pause;
 pub mod rootfs;
 pub mod user;
 pub mod workspace;
@@ -0,0 +1,326 @@
+//! The purpose of this program is to measure how long keg takes to start and stop a container, per
+//! stage, from the `--verbose` log.
+
+use crate::{msg_and, msg_ret, ok_or, some_or, true_or};
+use indoc::indoc;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, ExitCode};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static HELP_MESSAGE: &'static str = indoc! {r#"
+Usage: [OPTIONS] [--] [KEG ARGS]...
+
+Runs `<BIN> --verbose --log-file <FILE> [KEG ARGS]... -- /bin/true` repeatedly
+in a new temporary directory, for keg-base, keg and keg-rootfs, which must be
+next to this program. Prints the median, minimum and maximum time of:
+    Startup             From running <BIN> to isolation stage 0
+    <STAGE>             From entering <STAGE> to entering the next stage, or
+                        to executing the command for the exec stage
+    Command             From executing the command to its exit. For keg and
+                        keg-rootfs, the command is podman.
+    Teardown            From the exit of the command to the exit of <BIN>
+    Total               From running <BIN> to its exit
+
+Arguments:
+    [KEG ARGS]...       Arguments passed to every binary, e.g. --share-net
+
+Options:
+    --help              Display this message and exit
+    -n <COUNT>          Run each binary <COUNT> times. The default is 10.
+    --bin <NAME>        Only run <NAME>, which is keg-base, keg or keg-rootfs.
+                        This option can appear multiple times.
+    --budget <SECONDS>  Exit with 1 if the median total time of a binary
+                        exceeds <SECONDS>
+"#};
+
+const BINS: [&str; 3] = ["keg-base", "keg", "keg-rootfs"];
+
+struct Args {
+    count: usize,
+    bins: Vec<&'static str>,
+    budget: Option<f64>,
+    keg_args: Vec<OsString>,
+}
+
+fn handle_args() -> Option<Args> {
+    let mut args = env::args_os().peekable();
+    some_or!(args.next(), msg_ret!("Argument required"));
+
+    let mut count = 10;
+    let mut bins = Vec::new();
+    let mut budget = None;
+    let mut keg_args: Vec<OsString> = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if &arg == "--help" {
+            println!("{HELP_MESSAGE}");
+            process::exit(0);
+        } else if &arg == "-n" {
+            let count_arg = some_or!(args.next(), msg_ret!("-n requires an argument"));
+            count = some_or!(
+                (count_arg.into_string().ok())
+                    .and_then(|x| x.parse().ok())
+                    .filter(|x| *x > 0),
+                msg_ret!("Invalid count")
+            );
+        } else if &arg == "--bin" {
+            let bin_arg = some_or!(args.next(), msg_ret!("--bin requires an argument"));
+            bins.push(some_or!(
+                BINS.into_iter().find(|x| bin_arg == *x),
+                msg_ret!("Unknown binary {}", bin_arg.to_string_lossy())
+            ));
+        } else if &arg == "--budget" {
+            let budget_arg = some_or!(args.next(), msg_ret!("--budget requires an argument"));
+            budget = Some(some_or!(
+                (budget_arg.into_string().ok())
+                    .and_then(|x| x.parse().ok())
+                    .filter(|x: &f64| x.is_finite() && *x > 0.0),
+                msg_ret!("Invalid budget")
+            ));
+        } else if &arg == "--" || !arg.as_bytes().starts_with(b"-") {
+            if !arg.as_bytes().starts_with(b"-") {
+                keg_args.push(arg);
+            }
+            keg_args.extend(args.by_ref());
+            break;
+        } else {
+            msg_ret!("Unknown argument {}. Try --help.", arg.to_string_lossy());
+        }
+    }
+    if bins.is_empty() {
+        bins = BINS.to_vec();
+    }
+
+    Some(Args {
+        count,
+        bins,
+        budget,
+        keg_args,
+    })
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Returns the (phase, seconds) of a run from its `--verbose` log, given when it was started and
+/// when it exited, in seconds since the Unix epoch.
+fn phases(log: &str, started: f64, exited: f64) -> Option<Vec<(String, f64)>> {
+    let mut entered = Vec::new();
+    let mut executing = None;
+    let mut command_exited = None;
+    for line in log.lines() {
+        let line = some_or!(line.strip_prefix('['), continue);
+        let (time, rest) = some_or!(line.split_once("] "), continue);
+        let (stage, message) = some_or!(rest.split_once(": "), continue);
+        let time: f64 = some_or!(time.parse().ok(), continue);
+        if message == "Entered" {
+            entered.push((stage, time));
+        } else if stage == "Exec stage" && message.starts_with("Executing ") {
+            executing = Some(time);
+        } else if stage == "Exec stage" && message.starts_with("Exited with ") {
+            // The exec stage waits for the command, so this is when the command exited.
+            command_exited = Some(time);
+        }
+    }
+    let executing = executing?;
+    let command_exited = command_exited?;
+    // Stages write to the log independently, so lines are not always in the order of their times.
+    entered.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    let mut result = vec![("Startup".to_owned(), entered.first()?.1 - started)];
+    for (i, (stage, time)) in entered.iter().enumerate() {
+        let end = match entered.get(i + 1) {
+            Some((_, next)) => *next,
+            None => executing,
+        };
+        result.push((stage.to_string(), end - time));
+    }
+    result.push(("Command".to_owned(), command_exited - executing));
+    result.push(("Teardown".to_owned(), exited - command_exited));
+    result.push(("Total".to_owned(), exited - started));
+    Some(result)
+}
+
+/// Runs `bin` once in `dir`, and returns its phases.
+fn run_once(bin: &Path, keg_args: &[OsString], dir: &Path) -> Option<Vec<(String, f64)>> {
+    let work_dir = dir.join("work");
+    let log_file = dir.join("log");
+    ok_or!(
+        fs::create_dir(&work_dir),
+        msg_ret!("Failed to create {}", work_dir.display())
+    );
+
+    let started = now();
+    let status = Command::new(bin)
+        .arg("--verbose")
+        .arg("--log-file")
+        .arg(&log_file)
+        .args(keg_args)
+        .args(["--", "/bin/true"])
+        .current_dir(&work_dir)
+        .status();
+    let exited = now();
+    let status = ok_or!(status, msg_ret!("Cannot run {}", bin.display()));
+
+    let log = fs::read_to_string(&log_file).unwrap_or_default();
+    // Files created by keg-rootfs may be left over on error; they are not needed.
+    drop(fs::remove_dir_all(&work_dir));
+    if !status.success() {
+        eprint!("{log}");
+        msg_ret!("{} failed: {}", bin.display(), status);
+    }
+    Some(some_or!(
+        phases(&log, started, exited),
+        msg_ret!("Incomplete log of {}", bin.display())
+    ))
+}
+
+fn median(sorted: &[f64]) -> f64 {
+    let middle = sorted.len() / 2;
+    if sorted.len() % 2 == 1 {
+        sorted[middle]
+    } else {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    }
+}
+
+/// Runs `bin` `args.count` times, prints the phases, and returns the median total time.
+fn bench(bin: &str, args: &Args, dir: &Path) -> Option<f64> {
+    let exe = ok_or!(env::current_exe(), msg_ret!("Failed getting current exe"));
+    let bin_path = exe.with_file_name(bin);
+
+    // Phases in the order they first appear, since stages may be skipped.
+    let mut times: Vec<(String, Vec<f64>)> = Vec::new();
+    for _ in 0..args.count {
+        for (phase, time) in run_once(&bin_path, &args.keg_args, dir)? {
+            match times.iter_mut().find(|(x, _)| x == &phase) {
+                Some((_, phase_times)) => phase_times.push(time),
+                None => times.push((phase, vec![time])),
+            }
+        }
+    }
+
+    println!("{}: {} runs", bin, args.count);
+    println!(
+        "    {:<24}{:>12}{:>12}{:>12}",
+        "Phase", "Median", "Min", "Max"
+    );
+    let mut total = 0.0;
+    for (phase, phase_times) in &mut times {
+        phase_times.sort_unstable_by(f64::total_cmp);
+        let phase_median = median(phase_times);
+        if phase == "Total" {
+            total = phase_median;
+        }
+        println!(
+            "    {:<24}{:>10.3}ms{:>10.3}ms{:>10.3}ms",
+            phase,
+            phase_median * 1000.0,
+            phase_times[0] * 1000.0,
+            phase_times[phase_times.len() - 1] * 1000.0
+        );
+    }
+    Some(total)
+}
+
+fn run_all(args: &Args, dir: &Path) -> bool {
+    let mut within_budget = true;
+    for bin in &args.bins {
+        let total = some_or!(bench(bin, args, dir), return false);
+        if let Some(budget) = args.budget {
+            if total > budget {
+                eprintln!("{bin} exceeds the budget: {total:.6}s > {budget:.6}s");
+                within_budget = false;
+            }
+        }
+    }
+    within_budget
+}
+
+pub fn run() -> ExitCode {
+    let args = some_or!(handle_args(), return ExitCode::FAILURE);
+
+    let dir: PathBuf = env::temp_dir().join(format!("keg-bench-{}", process::id()));
+    ok_or!(
+        fs::create_dir(&dir),
+        msg_and!("Failed to create {}", dir.display(); return ExitCode::FAILURE)
+    );
+    let result = run_all(&args, &dir);
+    ok_or!(
+        fs::remove_dir_all(&dir),
+        msg_and!("Failed to remove {}", dir.display(); return ExitCode::FAILURE)
+    );
+    true_or!(result, return ExitCode::FAILURE);
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `--verbose` log of keg-base, where stages 1 to 5 are skipped, with the lines of
+    /// `Isolation stage 6` and `Mounting stage` out of order.
+    const LOG: &str = indoc! {"
+        [100.000000] Isolation stage 0: Entered
+        [100.001000] Isolation stage 0: Running /usr/bin/bwrap --unshare-user -- /keg-bin --inner 3
+        [100.030000] Mounting stage: Entered
+        [100.010000] Isolation stage 6: Entered
+        [100.020000] Isolation stage 0: Started Isolation stage 6 as pid 42 after 0.019000s
+        [100.060000] Exec stage: Entered
+        [100.065000] Exec stage: Executing /bin/true
+        [100.075000] Exec stage: Exited with exit status: 0 after 0.015000s
+        [100.080000] Mounting stage: Exited with exit status: 0 after 0.050000s
+        [100.090000] Isolation stage 6: Exited with exit status: 0 after 0.080000s
+        [100.095000] Isolation stage 0: Exited with exit status: 0 after 0.095000s
+    "};
+
+    fn assert_phases(phases: Vec<(String, f64)>, expected: &[(&str, f64)]) {
+        assert_eq!(phases.len(), expected.len(), "{phases:?}");
+        for ((phase, time), (expected_phase, expected_time)) in phases.iter().zip(expected) {
+            assert_eq!(phase, expected_phase);
+            assert!((time - expected_time).abs() < 1e-9, "{phase}: {time}");
+        }
+    }
+
+    #[test]
+    fn phases_of_log() {
+        assert_phases(
+            phases(LOG, 99.99, 100.1).unwrap(),
+            &[
+                ("Startup", 0.01),
+                ("Isolation stage 0", 0.01),
+                ("Isolation stage 6", 0.02),
+                ("Mounting stage", 0.03),
+                ("Exec stage", 0.005),
+                ("Command", 0.01),
+                ("Teardown", 0.025),
+                ("Total", 0.11),
+            ],
+        );
+    }
+
+    #[test]
+    fn incomplete_log() {
+        let without = |pattern| -> String {
+            LOG.lines()
+                .filter(|line| !line.contains(pattern))
+                .map(|line| line.to_owned() + "\n")
+                .collect()
+        };
+        assert!(phases(&without("Executing"), 99.99, 100.1).is_none());
+        assert!(phases(&without("Exec stage: Exited"), 99.99, 100.1).is_none());
+        assert!(phases(&without("Entered"), 99.99, 100.1).is_none());
+        assert!(phases("", 99.99, 100.1).is_none());
+        // Lines that are not log lines are ignored.
+        let log = format!("garbage\n[x] Exec stage: Entered\n{LOG}");
+        assert_eq!(phases(&log, 99.99, 100.1).unwrap().len(), 8);
+    }
+}
@@ -0,0 +1,49 @@
+//! The purpose of this program is to remove the cgroups left over by keg in the current cgroup,
+//! e.g. after keg was killed.
+
+use crate::cgroup::cleanup_cgroups;
+use crate::{msg_ret, some_or};
+use indoc::indoc;
+use std::env;
+use std::process::{self, ExitCode};
+
+static HELP_MESSAGE: &'static str = indoc! {r#"
+Usage: [OPTIONS]
+
+Removes the cgroups left over by keg in the current cgroup, e.g. after keg was
+killed, and moves the processes that keg moved out of the way back to the
+current cgroup. Cgroups in use by a running keg are kept. Run it from the
+shell keg was run from.
+
+Options:
+    --help              Display this message and exit
+    --dry-run           Print the cgroups that would be removed, without
+                        removing them
+"#};
+
+fn handle_args() -> Option<bool> {
+    let mut args = env::args_os();
+    some_or!(args.next(), msg_ret!("Argument required"));
+
+    let mut dry_run = false;
+    for arg in args {
+        if &arg == "--help" {
+            println!("{HELP_MESSAGE}");
+            process::exit(0);
+        } else if &arg == "--dry-run" {
+            dry_run = true;
+        } else {
+            msg_ret!("Unknown argument {}. Try --help.", arg.to_string_lossy());
+        }
+    }
+    Some(dry_run)
+}
+
+pub fn run() -> ExitCode {
+    let dry_run = some_or!(handle_args(), return ExitCode::FAILURE);
+    if cleanup_cgroups(dry_run) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
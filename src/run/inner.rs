@@ -1,63 +1,55 @@
-use crate::container::{run_container, ContainerRunner, ContainerRunnerResponse};
+use crate::container::{run_container, ContainerRunnerResponse};
+use crate::ipc;
+use crate::log;
 use crate::socket_pair::set_cloexec;
-use crate::{msg_ret, ok_or, true_or};
-use bincode;
+use crate::status::{exit_code, KEG_FAILURE};
+use crate::{debug, msg_ret, ok_or, true_or};
 use libc::c_int;
 use std::env;
-use std::io::{Read, Write};
 use std::os::unix::io::FromRawFd;
 use std::os::unix::net::UnixStream;
 use std::process::{self, ExitStatus};
 
 fn read_stream(stream: &mut UnixStream) -> Option<ExitStatus> {
-    let runner: ContainerRunner = ok_or!(
-        bincode::deserialize_from(&mut *stream),
-        msg_ret!("Deserialization failure")
-    );
+    let runner = match ipc::accept_stage(stream) {
+        Ok(runner) => runner,
+        Err(e) => msg_ret!("keg-bin: {}", e),
+    };
+    ipc::set_reporter(stream, runner.stage.clone());
+    log::init(runner.container.log_level, runner.container.log_fd);
+    debug!("Received {:?}", runner);
     let response = ContainerRunnerResponse {
         pid: ok_or!(process::id().try_into(), return None),
     };
-    ok_or!(
-        stream.write_all(&ok_or!(
-            bincode::serialize(&response),
-            msg_ret!("Send response failure")
-        )),
-        return None
-    );
-    ok_or!(stream.read_exact(&mut [0u8]), return None);
+    if let Err(e) = ipc::respond(stream, response) {
+        msg_ret!("Failed to start: {}", e);
+    }
     // We can manage our own cgroup at this point, which is required for `run_container`.
 
     run_container(runner.stage, &runner.container, &runner.env, true)
 }
 
 pub fn run() -> ! {
+    let failure = i32::from(KEG_FAILURE);
     let mut args = env::args_os();
     for _ in 0..2 {
         if args.next().is_none() {
-            process::exit(1);
+            process::exit(failure);
         }
     }
     let sock = match args.next() {
         Some(sock) => sock,
-        None => process::exit(1),
+        None => process::exit(failure),
     };
     let sock: c_int = match sock.into_string() {
         Ok(sock) => match sock.parse() {
             Ok(sock) => sock,
-            Err(_) => process::exit(1),
+            Err(_) => process::exit(failure),
         },
-        Err(_) => process::exit(1),
+        Err(_) => process::exit(failure),
     };
-    true_or!(unsafe { set_cloexec(sock) }, process::exit(1));
+    true_or!(unsafe { set_cloexec(sock) }, process::exit(failure));
 
     let mut stream = unsafe { UnixStream::from_raw_fd(sock) };
-    let exit_code = if let Some(exit_status) = read_stream(&mut stream) {
-        exit_status
-            .code()
-            .map(|c| ((((c % 256) + 256) % 256) as u8).into())
-            .unwrap_or(1)
-    } else {
-        1
-    };
-    process::exit(exit_code)
+    process::exit(exit_code(read_stream(&mut stream)).into())
 }
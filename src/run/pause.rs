@@ -0,0 +1,65 @@
+//! The purpose of this program is to freeze or thaw all processes of a running container named
+//! with `--name`.
+
+use crate::cgroup::{container_cgroup_of, freeze};
+use crate::registry;
+use crate::{msg_ret, some_or};
+use indoc::indoc;
+use std::env;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::process::{self, ExitCode};
+
+static PAUSE_HELP_MESSAGE: &'static str = indoc! {r#"
+Usage: [OPTIONS] <NAME>
+
+Freezes all processes of the running container <NAME>, which was started with
+--name <NAME>, until they are thawed by keg-resume. Frozen processes do not
+run and cannot be killed except by SIGKILL, but keep their memory.
+
+Options:
+    --help              Display this message and exit
+"#};
+
+static RESUME_HELP_MESSAGE: &'static str = indoc! {r#"
+Usage: [OPTIONS] <NAME>
+
+Thaws all processes of the running container <NAME>, which were frozen by
+keg-pause or --freeze-on-suspend.
+
+Options:
+    --help              Display this message and exit
+"#};
+
+fn handle_args(resume: bool) -> Option<OsString> {
+    let mut args = env::args_os();
+    some_or!(args.next(), msg_ret!("Argument required"));
+
+    let mut name = None;
+    for arg in args {
+        if &arg == "--help" {
+            if resume {
+                println!("{RESUME_HELP_MESSAGE}");
+            } else {
+                println!("{PAUSE_HELP_MESSAGE}");
+            }
+            process::exit(0);
+        } else if arg.as_bytes().starts_with(b"-") || name.is_some() {
+            msg_ret!("Unknown argument {}. Try --help.", arg.to_string_lossy());
+        } else {
+            name = Some(arg);
+        }
+    }
+    Some(some_or!(name, msg_ret!("<NAME> required. Try --help.")))
+}
+
+pub fn run(resume: bool) -> ExitCode {
+    let name = some_or!(handle_args(resume), return ExitCode::FAILURE);
+    let pid = some_or!(registry::lookup(&name), return ExitCode::FAILURE);
+    let cgroup = some_or!(container_cgroup_of(pid), return ExitCode::FAILURE);
+    if freeze(&cgroup, !resume) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
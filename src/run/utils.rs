@@ -1,10 +1,50 @@
-use crate::some_or;
+use crate::cgroup::{enter_new_cgroup, has_systemd_user_session};
+use crate::container::{
+    Container, DummyProc, Host, Options, SetEnv, Slirp, TimeOffsets, DEFAULT_HOSTNAME,
+};
+use crate::dummy_proc::{self, FILE_NAMES};
+use crate::masked_paths;
+use crate::socket_pair::clear_cloexec;
+use crate::status::KEG_FAILURE;
+use crate::{msg_ret, msg_retf, ok_or, some_or, some_or_ret, true_or};
+use libc::{c_int, clock_gettime, clockid_t, timespec, CLOCK_BOOTTIME, CLOCK_MONOTONIC};
 use std::env;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::IntoRawFd;
 use std::os::unix::process::CommandExt;
 use std::process::{Command, ExitCode};
+use std::time::Duration;
 
-pub fn run_in_scope() -> ExitCode {
+/// Environment of the command with `--hermetic`. `SOURCE_DATE_EPOCH` is 1980-01-01, the earliest
+/// time stored by zip.
+const HERMETIC_ENV: [(&str, &str); 3] = [
+    ("SOURCE_DATE_EPOCH", "315532800"),
+    ("TZ", "UTC"),
+    ("LC_ALL", "C"),
+];
+
+/// Uptime of the container when it starts with `--hermetic`, as in the dummy `/proc/uptime`.
+const HERMETIC_UPTIME: Duration = Duration::from_secs(100);
+
+/// Largest offset of a clock in seconds that the kernel accepts, `KTIME_SEC_MAX / 2`.
+const MAX_TIME_OFFSET: i64 = i64::MAX / 1_000_000_000 / 2;
+
+/// Runs keg in a new cgroup: a systemd scope if there is a systemd user session, and a new cgroup
+/// under the current one otherwise. Returns the exit code if keg was run in a systemd scope, or
+/// failed.
+pub fn new_scope() -> Option<ExitCode> {
+    if has_systemd_user_session() {
+        return Some(run_in_scope());
+    }
+    true_or!(enter_new_cgroup(), return Some(KEG_FAILURE.into()));
+    None
+}
+
+fn run_in_scope() -> ExitCode {
     // Run in a new systemd scope.
     let mut args = Vec::<OsString>::new();
     args.push("--user".into());
@@ -12,12 +52,658 @@ pub fn run_in_scope() -> ExitCode {
     args.push("-q".into());
     args.push("--".into());
     let mut caller_args = env::args_os();
-    args.push(some_or!(caller_args.next(), return ExitCode::FAILURE));
+    args.push(some_or!(caller_args.next(), return KEG_FAILURE.into()));
     args.push("--no-new-scope".into());
     while let Some(arg) = caller_args.next() {
         args.push(arg);
     }
     let error = Command::new("systemd-run").args(args).exec();
     eprintln!("Failed to run `systemd-run --user --scope ...`: {error}");
-    ExitCode::FAILURE
+    KEG_FAILURE.into()
+}
+
+/// Checks `name` is a valid hostname as per RFC 1123.
+fn is_valid_hostname(name: &[u8]) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.split(|c| c == &b'.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with(b"-")
+                && !label.ends_with(b"-")
+                && label
+                    .iter()
+                    .all(|c| c.is_ascii_alphanumeric() || c == &b'-')
+        })
+}
+
+pub fn parse_hostname(arg: OsString) -> Option<OsString> {
+    true_or!(
+        is_valid_hostname(arg.as_bytes()),
+        msg_ret!("Invalid hostname {}", arg.to_string_lossy())
+    );
+    Some(arg)
+}
+
+pub fn parse_ip(arg: OsString) -> Option<IpAddr> {
+    Some(some_or!(
+        arg.to_str().and_then(|x| x.parse().ok()),
+        msg_ret!("Invalid IP address {}", arg.to_string_lossy())
+    ))
+}
+
+/// Parses `<NAME>:<IP>`. <IP> may contain ':'.
+pub fn parse_host(arg: OsString) -> Option<Host> {
+    let bytes = arg.as_bytes();
+    let colon = some_or!(
+        bytes.iter().position(|c| c == &b':'),
+        msg_ret!(
+            "Invalid host {}, expected <NAME>:<IP>",
+            arg.to_string_lossy()
+        )
+    );
+    let name = OsString::from_vec(bytes[..colon].to_vec());
+    let ip = OsString::from_vec(bytes[colon + 1..].to_vec());
+    Some(Host {
+        name: some_or_ret!(parse_hostname(name)),
+        ip: some_or_ret!(parse_ip(ip)),
+    })
+}
+
+/// Returns the time of `clock` in nanoseconds.
+fn clock_nsec(clock: clockid_t) -> Option<i64> {
+    let mut now = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    true_or!(
+        unsafe { clock_gettime(clock, &mut now) } == 0,
+        msg_ret!("Cannot read clock {}", clock)
+    );
+    Some(now.tv_sec * 1_000_000_000 + now.tv_nsec)
+}
+
+/// Applies `--hermetic` to `container`: a fixed hostname, environment and uptime. A time namespace
+/// cannot change the wall clock, so builds are expected to use `SOURCE_DATE_EPOCH` instead.
+#[must_use]
+pub fn set_hermetic(container: &mut Container) -> bool {
+    if container.hostname.is_none() {
+        container.hostname = Some(DEFAULT_HOSTNAME.into());
+    }
+    // Before the options of the user, which can override them.
+    container.options.splice(
+        0..0,
+        HERMETIC_ENV.map(|(key, value)| {
+            Options::SetEnv(SetEnv {
+                key: key.into(),
+                value: value.into(),
+            })
+        }),
+    );
+    // Added to `--time-offset` and `--boot-time-offset`.
+    let uptime = HERMETIC_UPTIME.as_nanos() as i64;
+    let offsets = container
+        .time_offsets
+        .get_or_insert_with(TimeOffsets::default);
+    for (offset, clock, option) in [
+        (&mut offsets.monotonic, CLOCK_MONOTONIC, "--time-offset"),
+        (&mut offsets.boottime, CLOCK_BOOTTIME, "--boot-time-offset"),
+    ] {
+        let now = some_or!(clock_nsec(clock), return false);
+        *offset = some_or!(
+            uptime
+                .checked_sub(now)
+                .and_then(|x| offset.checked_add(x))
+                .filter(|x| (x / 1_000_000_000).unsigned_abs() <= MAX_TIME_OFFSET as u64),
+            msg_retf!(
+                "Invalid time offset, {} is out of range with --hermetic",
+                option
+            )
+        );
+    }
+    true
+}
+
+/// Applies `--private-identity` to `container`: a fixed hostname, and dummy files with a random
+/// `/etc/machine-id`. Whether the dummy files replace `/proc` and `/etc` in place is left to the
+/// caller.
+#[must_use]
+pub fn set_private_identity(container: &mut Container) -> bool {
+    if container.hostname.is_none() {
+        container.hostname = Some(DEFAULT_HOSTNAME.into());
+    }
+    let mut bytes = [0; 16];
+    if let Err(e) = File::open("/dev/urandom").and_then(|mut file| file.read_exact(&mut bytes)) {
+        msg_retf!("Cannot read /dev/urandom: {}", e);
+    }
+    let machine_id: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    let dummy_proc = container.dummy_proc.get_or_insert_with(DummyProc::default);
+    dummy_proc.machine_id = Some(machine_id);
+    true
+}
+
+/// Parses a container name, which may contain ASCII letters, digits, '_', '-' and '.', and may
+/// not start with '.'.
+pub fn parse_name(arg: OsString) -> Option<OsString> {
+    let name = arg.as_bytes();
+    true_or!(
+        !name.is_empty()
+            && name.len() <= 64
+            && !name.starts_with(b".")
+            && name
+                .iter()
+                .all(|c| c.is_ascii_alphanumeric() || b"_-.".contains(c)),
+        msg_ret!("Invalid container name {}", arg.to_string_lossy())
+    );
+    Some(arg)
+}
+
+/// Parses the argument of `--net`. Only `container:<NAME>` is supported.
+pub fn parse_net(arg: OsString) -> Option<OsString> {
+    let name = some_or!(
+        arg.as_bytes().strip_prefix(b"container:"),
+        msg_ret!(
+            "Invalid network mode {}, expected container:<NAME>",
+            arg.to_string_lossy()
+        )
+    );
+    parse_name(OsString::from_vec(name.to_vec()))
+}
+
+/// Parses an IPv4 CIDR accepted by slirp4netns, e.g. `10.0.2.0/24`. Host bits must not be set.
+pub fn parse_cidr(arg: OsString) -> Option<OsString> {
+    let valid = arg
+        .to_str()
+        .and_then(|x| x.split_once('/'))
+        .is_some_and(|(ip, prefix)| {
+            let ip = ok_or!(ip.parse::<Ipv4Addr>(), return false);
+            let prefix = ok_or!(prefix.parse::<u8>(), return false);
+            (1..=25).contains(&prefix) && u32::from(ip) & (u32::MAX >> prefix) == 0
+        });
+    true_or!(
+        valid,
+        msg_ret!(
+            "Invalid CIDR {}, expected an IPv4 network with a prefix length of 1-25",
+            arg.to_string_lossy()
+        )
+    );
+    Some(arg)
+}
+
+pub fn parse_mtu(arg: OsString) -> Option<u32> {
+    let mtu = some_or!(
+        arg.to_str().and_then(|x| x.parse().ok()),
+        msg_ret!("Invalid MTU {}", arg.to_string_lossy())
+    );
+    true_or!(
+        (68..=65521).contains(&mtu),
+        msg_ret!("MTU must be between 68 and 65521")
+    );
+    Some(mtu)
+}
+
+/// Parses an offset of a clock as nanoseconds: a signed number of seconds, or of minutes, hours or
+/// days with the suffix `m`, `h` or `d`, e.g. `-90`, `30m` or `7d`, within the range of the kernel.
+pub fn parse_time_offset(arg: OsString) -> Option<i64> {
+    let offset = arg.to_str().and_then(|x| {
+        let (number, unit) = match x.strip_suffix(['s', 'm', 'h', 'd']) {
+            Some(number) => (number, &x[number.len()..]),
+            None => (x, "s"),
+        };
+        let unit = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            _ => 24 * 60 * 60,
+        };
+        number
+            .parse::<i64>()
+            .ok()?
+            .checked_mul(unit)
+            .filter(|x| x.unsigned_abs() <= MAX_TIME_OFFSET as u64)?
+            .checked_mul(1_000_000_000)
+    });
+    Some(some_or!(
+        offset,
+        msg_ret!(
+            "Invalid time offset {}, expected e.g. -90, 30m or 7d",
+            arg.to_string_lossy()
+        )
+    ))
+}
+
+/// Parses the number of CPUs of the dummy `/proc/stat`.
+pub fn parse_dummy_cpus(arg: OsString) -> Option<u32> {
+    let cpus = some_or!(
+        arg.to_str().and_then(|x| x.parse().ok()),
+        msg_ret!("Invalid number of CPUs {}", arg.to_string_lossy())
+    );
+    true_or!(
+        (1..=4096).contains(&cpus),
+        msg_ret!("The number of CPUs must be between 1 and 4096")
+    );
+    Some(cpus)
+}
+
+/// Parses the arguments of `--dummy-proc-file`, and reads the file.
+pub fn parse_dummy_proc_file<A>(args: &mut A) -> Option<(String, Vec<u8>)>
+where
+    A: Iterator<Item = OsString>,
+{
+    let name = some_or!(
+        args.next(),
+        msg_ret!("--dummy-proc-file requires 2 arguments")
+    );
+    let path = some_or!(
+        args.next(),
+        msg_ret!("--dummy-proc-file requires 2 arguments")
+    );
+    let name = some_or!(
+        name.to_str().filter(|x| FILE_NAMES.contains(x)),
+        msg_ret!(
+            "Invalid dummy /proc file {}, expected loadavg, stat or uptime",
+            name.to_string_lossy()
+        )
+    );
+    let contents = ok_or!(
+        fs::read(&path),
+        msg_ret!("Failed to read {}", path.to_string_lossy())
+    );
+    Some((name.to_owned(), contents))
+}
+
+/// Returns where a bind with destination `dest` is mounted in the podman container, `/mnt/<DEST>`,
+/// or why `dest` is invalid.
+pub fn mnt_bind_dest(dest: &OsStr) -> Result<OsString, &'static str> {
+    let dest = dest.as_bytes();
+    if dest.contains(&b'/') {
+        return Err("Bind destination cannot contain \"/\"");
+    }
+    if dest.contains(&b'\0') {
+        return Err("Bind destination cannot contain the nul byte");
+    }
+    if dest == b"." {
+        return Err("Bind destination cannot be \".\"");
+    }
+    if dest == b".." {
+        return Err("Bind destination cannot be \"..\"");
+    }
+    if dest.is_empty() {
+        return Err("Bind destination cannot be empty");
+    }
+    Ok(OsString::from_vec([&b"/mnt/"[..], dest].concat()))
+}
+
+/// Returns the `--network` argument of podman.
+fn podman_network_arg(slirp: &Slirp) -> OsString {
+    let mut arg = OsString::from("--network=slirp4netns");
+    let mut separator = ":";
+    if let Some(cidr) = &slirp.cidr {
+        arg.push(separator);
+        arg.push("cidr=");
+        arg.push(cidr);
+        separator = ",";
+    }
+    if let Some(mtu) = slirp.mtu {
+        arg.push(separator);
+        arg.push(format!("mtu={mtu}"));
+        separator = ",";
+    }
+    if slirp.disable_ipv6 {
+        arg.push(separator);
+        arg.push("enable_ipv6=false");
+    }
+    arg
+}
+
+/// Returns the command run in the container: podman, running `command` or `/bin/bash` in
+/// `/container_rootfs`. `extra_args` are added after the mounts of keg, and `container_args` after
+/// `--rootfs`.
+pub fn podman_command(
+    container: &Container,
+    host_network: bool,
+    hosts: &[Host],
+    dns: &[IpAddr],
+    extra_args: &[&str],
+    container_args: &[OsString],
+    command: &[OsString],
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![
+        "/usr/bin/podman".into(),
+        "run".into(),
+        // cap_sys_chroot: https://github.com/containers/podman/issues/17504
+        "--cap-add".into(),
+        "sys_chroot".into(),
+        "-i".into(),
+        masked_paths::podman_arg().into(),
+    ];
+    if container.private_identity {
+        args.push(masked_paths::private_identity_podman_arg().into());
+    }
+    if container.no_cgroup {
+        args.push("--cgroups=disabled".into());
+    }
+    if host_network {
+        args.push("--network=host".into());
+    } else {
+        args.push(podman_network_arg(&container.slirp));
+    }
+    if let Some(hostname) = &container.hostname {
+        let mut arg = OsString::from("--hostname=");
+        arg.push(hostname);
+        args.push(arg);
+    }
+    if container.hermetic {
+        args.push("--tz=UTC".into());
+        for (key, value) in HERMETIC_ENV {
+            args.push(format!("--env={key}={value}").into());
+        }
+    }
+    for Host { name, ip } in hosts {
+        let mut arg = OsString::from("--add-host=");
+        arg.push(name);
+        arg.push(":");
+        arg.push(ip.to_string());
+        args.push(arg);
+    }
+    for ip in dns {
+        args.push(format!("--dns={ip}").into());
+    }
+    args.push("-t".into());
+    args.push("--mount=type=tmpfs,dst=/tmp".into());
+    if let Some(dummy_proc) = &container.dummy_proc {
+        for file_name in dummy_proc::file_names(dummy_proc) {
+            for dest in dummy_proc::dests(file_name) {
+                args.push(
+                    format!(
+                        "--mount=type=bind,src=/container_dummy_{file_name},dst={},ro=true",
+                        dest.display()
+                    )
+                    .into(),
+                );
+            }
+        }
+    }
+    args.extend(extra_args.iter().map(OsString::from));
+    args.push("--rootfs".into());
+    args.extend(container_args.iter().cloned());
+    args.push("/container_rootfs".into());
+    if command.is_empty() {
+        args.push("/bin/bash".into());
+    } else {
+        args.extend(command.iter().cloned());
+    }
+    args
+}
+
+/// Creates the file at `path`, and returns a file descriptor to it that is inherited by the
+/// stages, e.g. for the net log or the log file.
+pub fn open_inherited(path: &OsStr) -> Option<c_int> {
+    let file = ok_or!(
+        File::create(path),
+        msg_ret!("Failed to create \"{}\"", path.to_string_lossy())
+    );
+    let fd = file.into_raw_fd();
+    true_or!(
+        unsafe { clear_cloexec(fd) },
+        msg_ret!(
+            "Failed to pass \"{}\" to the container",
+            path.to_string_lossy()
+        )
+    );
+    Some(fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden;
+
+    fn lines(args: &[OsString]) -> String {
+        args.iter().map(|x| x.to_string_lossy() + "\n").collect()
+    }
+
+    #[test]
+    fn mnt_bind_dest_rules() {
+        assert_eq!(mnt_bind_dest(OsStr::new("data")), Ok("/mnt/data".into()));
+        assert_eq!(mnt_bind_dest(OsStr::new("...")), Ok("/mnt/...".into()));
+        assert_eq!(
+            mnt_bind_dest(OsStr::new("a/b")),
+            Err("Bind destination cannot contain \"/\"")
+        );
+        assert_eq!(
+            mnt_bind_dest(OsStr::new("/")),
+            Err("Bind destination cannot contain \"/\"")
+        );
+        assert_eq!(
+            mnt_bind_dest(OsStr::new("a\0b")),
+            Err("Bind destination cannot contain the nul byte")
+        );
+        assert_eq!(
+            mnt_bind_dest(OsStr::new(".")),
+            Err("Bind destination cannot be \".\"")
+        );
+        assert_eq!(
+            mnt_bind_dest(OsStr::new("..")),
+            Err("Bind destination cannot be \"..\"")
+        );
+        assert_eq!(
+            mnt_bind_dest(OsStr::new("")),
+            Err("Bind destination cannot be empty")
+        );
+    }
+
+    #[test]
+    fn hostnames() {
+        let valid = |name: &str| parse_hostname(name.into()).is_some();
+        assert!(valid("box"));
+        assert!(valid("build-1.example.com"));
+        assert!(valid(&"a".repeat(63)));
+        assert!(!valid(&"a".repeat(64)));
+        assert!(!valid(&format!("{}.{}", "a".repeat(32), "b".repeat(32))));
+        assert!(!valid(""));
+        assert!(!valid("a..b"));
+        assert!(!valid(".a"));
+        assert!(!valid("-a"));
+        assert!(!valid("a-"));
+        assert!(!valid("a_b"));
+        assert!(!valid("a b"));
+    }
+
+    #[test]
+    fn ips() {
+        assert_eq!(parse_ip("1.1.1.1".into()), Some("1.1.1.1".parse().unwrap()));
+        assert_eq!(parse_ip("::1".into()), Some("::1".parse().unwrap()));
+        assert_eq!(parse_ip("1.1.1".into()), None);
+        assert_eq!(parse_ip("".into()), None);
+    }
+
+    #[test]
+    fn hosts() {
+        let host = |name: &str, ip: &str| {
+            Some(Host {
+                name: name.into(),
+                ip: ip.parse().unwrap(),
+            })
+        };
+        assert_eq!(parse_host("db:10.1.0.5".into()), host("db", "10.1.0.5"));
+        assert_eq!(parse_host("db:::1".into()), host("db", "::1"));
+        assert_eq!(
+            parse_host("db:2001:db8::1".into()),
+            host("db", "2001:db8::1")
+        );
+        assert_eq!(parse_host("db".into()), None);
+        assert_eq!(parse_host("db10.1.0.5".into()), None);
+        assert_eq!(parse_host(":10.1.0.5".into()), None);
+        assert_eq!(parse_host("db:".into()), None);
+        assert_eq!(parse_host("d_b:10.1.0.5".into()), None);
+    }
+
+    #[test]
+    fn names() {
+        let valid = |name: &str| parse_name(name.into()).is_some();
+        assert!(valid("build"));
+        assert!(valid("build_1.x-y"));
+        assert!(valid(&"a".repeat(64)));
+        assert!(!valid(&"a".repeat(65)));
+        assert!(!valid(""));
+        assert!(!valid(".build"));
+        assert!(!valid("a/b"));
+        assert!(!valid("a b"));
+    }
+
+    #[test]
+    fn nets() {
+        assert_eq!(parse_net("container:build".into()), Some("build".into()));
+        assert_eq!(parse_net("container:".into()), None);
+        assert_eq!(parse_net("container:../x".into()), None);
+        assert_eq!(parse_net("host".into()), None);
+        assert_eq!(parse_net("build".into()), None);
+        assert_eq!(parse_net("".into()), None);
+    }
+
+    #[test]
+    fn cidrs() {
+        let valid = |cidr: &str| parse_cidr(cidr.into()).is_some();
+        assert!(valid("10.0.2.0/24"));
+        assert!(valid("10.0.0.0/8"));
+        assert!(valid("128.0.0.0/1"));
+        assert!(valid("10.1.0.128/25"));
+        assert!(!valid("10.0.2.0/0"));
+        assert!(!valid("10.0.2.0/26"));
+        assert!(!valid("10.0.2.0/33"));
+        assert!(!valid("10.0.2.0/-1"));
+        assert!(!valid("10.0.2.5/24"));
+        assert!(!valid("10.0.2.0"));
+        assert!(!valid("10.0.2/24"));
+        assert!(!valid("fd00::/64"));
+        assert!(!valid(""));
+    }
+
+    #[test]
+    fn mtus() {
+        assert_eq!(parse_mtu("68".into()), Some(68));
+        assert_eq!(parse_mtu("1500".into()), Some(1500));
+        assert_eq!(parse_mtu("65521".into()), Some(65521));
+        assert_eq!(parse_mtu("67".into()), None);
+        assert_eq!(parse_mtu("65522".into()), None);
+        assert_eq!(parse_mtu("-1".into()), None);
+        assert_eq!(parse_mtu("1500b".into()), None);
+    }
+
+    #[test]
+    fn time_offsets() {
+        let parse = |x: &str| parse_time_offset(x.into());
+        assert_eq!(parse("90"), Some(90_000_000_000));
+        assert_eq!(parse("-90s"), Some(-90_000_000_000));
+        assert_eq!(parse("+30m"), Some(1_800_000_000_000));
+        assert_eq!(parse("2h"), Some(7_200_000_000_000));
+        assert_eq!(parse("-7d"), Some(-604_800_000_000_000));
+        assert_eq!(parse("1.5h"), None);
+        assert_eq!(parse("h"), None);
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("9223372037s"), None);
+        assert_eq!(parse("-4611686018"), Some(-4_611_686_018_000_000_000));
+        assert_eq!(parse("4611686019"), None);
+        assert_eq!(parse("53376d"), None);
+        assert_eq!(parse("-9223372036854775808"), None);
+    }
+
+    /// The container of keg and keg-rootfs without `--real-proc`.
+    fn container() -> Container {
+        Container {
+            dummy_proc: Some(DummyProc::default()),
+            ..Container::default()
+        }
+    }
+
+    #[test]
+    fn podman_command_default() {
+        let args = podman_command(&container(), false, &[], &[], &[], &[], &[]);
+        golden::check("podman_default.txt", &lines(&args));
+    }
+
+    #[test]
+    fn podman_command_options() {
+        let container = Container {
+            hostname: Some("box".into()),
+            slirp: Slirp {
+                cidr: Some("10.1.0.0/24".into()),
+                mtu: Some(1500),
+                disable_ipv6: true,
+                dns_upstream: None,
+            },
+            ..container()
+        };
+        let hosts = [Host {
+            name: "db".into(),
+            ip: "10.1.0.5".parse().unwrap(),
+        }];
+        let dns = ["1.1.1.1".parse().unwrap(), "::1".parse().unwrap()];
+        let args = podman_command(
+            &container,
+            false,
+            &hosts,
+            &dns,
+            &["-w=/root"],
+            &["--env=A=1".into()],
+            &["ls".into(), "-l".into()],
+        );
+        golden::check("podman_options.txt", &lines(&args));
+    }
+
+    #[test]
+    fn podman_command_host_network() {
+        let container = Container {
+            slirp: Slirp {
+                mtu: Some(1500),
+                ..Slirp::default()
+            },
+            ..container()
+        };
+        let args = podman_command(&container, true, &[], &[], &[], &[], &[]);
+        golden::check("podman_host_network.txt", &lines(&args));
+    }
+
+    #[test]
+    fn podman_command_no_cgroup() {
+        let container = Container {
+            no_cgroup: true,
+            ..container()
+        };
+        let args = podman_command(&container, false, &[], &[], &[], &[], &[]);
+        golden::check("podman_no_cgroup.txt", &lines(&args));
+    }
+
+    #[test]
+    fn podman_command_real_proc() {
+        let args = podman_command(&Container::default(), false, &[], &[], &[], &[], &[]);
+        golden::check("podman_real_proc.txt", &lines(&args));
+    }
+
+    #[test]
+    fn podman_command_hermetic() {
+        let container = Container {
+            hermetic: true,
+            hostname: Some(DEFAULT_HOSTNAME.into()),
+            ..container()
+        };
+        let args = podman_command(&container, false, &[], &[], &[], &[], &[]);
+        golden::check("podman_hermetic.txt", &lines(&args));
+    }
+
+    #[test]
+    fn podman_command_private_identity() {
+        let container = Container {
+            private_identity: true,
+            hostname: Some(DEFAULT_HOSTNAME.into()),
+            dummy_proc: Some(DummyProc {
+                machine_id: Some("0123456789abcdef0123456789abcdef".into()),
+                ..DummyProc::default()
+            }),
+            ..Container::default()
+        };
+        let args = podman_command(&container, false, &[], &[], &[], &[], &[]);
+        golden::check("podman_private_identity.txt", &lines(&args));
+    }
 }
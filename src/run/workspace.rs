@@ -1,18 +1,28 @@
-use super::utils::run_in_scope;
-use crate::container::{start_container, Bind, Container, Mount, Options, SetEnv};
+use super::utils::{
+    mnt_bind_dest, new_scope, open_inherited, parse_cidr, parse_dummy_cpus, parse_dummy_proc_file,
+    parse_host, parse_hostname, parse_ip, parse_mtu, parse_name, parse_net, parse_time_offset,
+    podman_command, set_hermetic, set_private_identity,
+};
+use crate::container::{
+    check_nft_rules, print_plan, start_container, Bind, Container, DummyProc, Host, Mount, Options,
+    SetEnv, TimeOffsets,
+};
 use crate::die_with_parent::set_die_with_parent;
 use crate::filesystem;
-use crate::masked_paths;
+use crate::log;
 use crate::overlayfs;
+use crate::registry;
 use crate::run::inner;
+use crate::status::{exit_code, exit_status, write_status_file, KEG_FAILURE};
 use crate::{msg_and, msg_ret, ok_or, some_or, some_or_ret, true_or};
 use indoc::indoc;
 use std::env;
 use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
-use std::process::{self, ExitCode};
+use std::process::{self, ExitCode, ExitStatus};
 
 macro_rules! help_message_part0 {
     () => {
@@ -27,15 +37,109 @@ Options:
     --help              Display this message and exit
     --no-die-with-parent
                         Do not kill child processes when this process dies
-    --no-new-scope      Do not run in a new systemd scope
+    --no-new-scope      Do not run in a new cgroup. By default, keg runs in a
+                        new systemd scope, or without a systemd user session,
+                        in a new cgroup under its current one. keg moves all
+                        processes of the cgroup it runs in to a child cgroup.
+    --no-cgroup         Do not use cgroups. The command cannot create cgroups, and podman runs with --cgroups=disabled.
     -b <PATH>           Use <PATH> as the trusted base image, instead of the
                         default system directories
     --share-net         Enable network
     --share-time        Share time namespace
+    --time-offset <OFFSET>
+                        Add <OFFSET> to the monotonic clock of the container,
+                        in seconds, or minutes, hours or days with the suffix
+                        m, h or d, e.g. -90, 30m or 7d
+    --boot-time-offset <OFFSET>
+                        Add <OFFSET> to the boot time clock of the container,
+                        which is its uptime, like --time-offset. With
+                        --hermetic, both offsets are added to 100s.
+    --hermetic          Make builds reproducible: disable network, set a fixed
+                        hostname, SOURCE_DATE_EPOCH=315532800, TZ=UTC and
+                        LC_ALL=C, set the time zone of podman to UTC, and
+                        start the monotonic and boot time clocks at 100s.
+                        This cannot be used with --share-net, --share-time
+                        or --net.
+    --real-proc         Do not replace /proc/loadavg, /proc/stat and
+                        /proc/uptime with dummy files, which hide the load,
+                        CPU time and uptime of the host
+    --dummy-cpus <N>    Advertise <N> CPUs in the dummy /proc/stat. The
+                        default is 1.
+    --dummy-proc-file <NAME> <PATH>
+                        Use the contents of <PATH> as the dummy /proc/<NAME>,
+                        where <NAME> is loadavg, stat or uptime. This option
+                        can appear multiple times.
+    --private-identity  Hide the identity of the host: set a fixed hostname,
+                        generate a random /etc/machine-id, also used as
+                        /var/lib/dbus/machine-id, and a dummy /proc/cpuinfo,
+                        mask the DMI, firmware and hardware entries of /sys,
+                        /proc/version, and /etc/adjtime, /etc/crypttab,
+                        /etc/fstab and /etc/machine-info. This cannot be used
+                        with --real-proc. The kernel release, as in uname -r,
+                        cannot be hidden.
     --net-nft-rules <PATH>
                         Read and enforce nftables rules from <PATH>. This file
                         will be loaded into memory and keg does not limit its
-                        size.
+                        size. The rules are checked before the container
+                        starts.
+    --check-nft-rules <PATH>
+                        Check nftables rules in <PATH> and exit
+    --net-log <PATH>    Count packets, bytes and new connections of the
+                        container per remote address, protocol and port, log
+                        each new connection with its time, and write a summary
+                        to <PATH> when the container exits. Connections are
+                        logged through nflog, which needs the nfnetlink_log
+                        kernel module. The rules run at priority -10, before
+                        the chains of --net-nft-rules at priority 0 or later,
+                        so traffic these drop is counted as well.
+    --status-file <PATH>
+                        Write how the container exited to <PATH> as JSON,
+                        including the stage and the error if keg failed
+    --stats             Print the wall time, CPU time, peak memory, I/O bytes
+                        and peak number of processes of the container to
+                        stderr when it exits. This includes podman.
+    --stats-file <PATH> Write the --stats of the container to <PATH> as JSON
+    --freeze-on-suspend Freeze all processes of the container while keg is
+                        suspended, e.g. by Ctrl-Z, until it is continued, e.g.
+                        by fg or bg. See also keg-pause and keg-resume.
+    --keep-running      Do not kill the processes left in the container when
+                        the command exits, or --stop-timeout after keg
+                        receives SIGINT, SIGTERM or SIGHUP
+    --stop-timeout <SECONDS>
+                        Kill all processes of the container if it does not
+                        exit within <SECONDS> after keg receives SIGINT,
+                        SIGTERM or SIGHUP. The default is 10.
+    --verbose           Log stage transitions and their timing, the command
+                        lines of bwrap, slirp4netns, nft and the command, and
+                        cgroup moves
+    --debug             Log everything --verbose logs, and the configuration
+                        sent to each stage
+    --log-file <PATH>   Write the log to <PATH> instead of stderr
+    --dry-run           Print the namespaces, capabilities, mounts and actions
+                        of each stage, and the environment and command of the
+                        container, without starting anything
+    --dry-run-json      Like --dry-run, as JSON
+    --name <NAME>       Name the container <NAME>, so other containers can
+                        refer to it. This requires $XDG_RUNTIME_DIR. podman
+                        will share the network namespace of keg.
+    --net container:<NAME>
+                        Join the network namespace of the running container
+                        <NAME>. Its nftables rules also apply to this
+                        container. podman will share the network namespace
+                        of keg.
+    --cidr <CIDR>       Use <CIDR> as the network of slirp4netns. The default
+                        is 10.0.2.0/24.
+    --mtu <MTU>         Use <MTU> as the MTU of slirp4netns
+    --disable-ipv6      Disable IPv6 in slirp4netns
+    --dns-upstream <IP> Use <IP> as the upstream name server of the DNS
+                        forwarder of slirp4netns, instead of the name servers
+                        in /etc/resolv.conf of the base image
+    --hostname <NAME>   Set the hostname of the container to <NAME>
+    --add-host <NAME>:<IP>
+                        Add an entry mapping <NAME> to <IP> to /etc/hosts.
+                        This option can appear multiple times.
+    --dns <IP>          Add <IP> as a name server to /etc/resolv.conf. This
+                        option can appear multiple times.
     -r <DIR>            Use <DIR> as the root directory. By default, /bin,
                         /etc, /lib, /opt, /sbin, /usr, /var, and /lib64 (if
                         /lib64 is available) will be made available in the
@@ -63,6 +167,18 @@ macro_rules! help_message_part1 {
                         allow device access, before running podman
     -a <ARG>            Append <ARG> as an argument to the podman. This can be
                         used to make additional changes to the container.
+
+Signals:
+    keg forwards SIGINT, SIGTERM, SIGHUP, SIGUSR1, SIGUSR2 and SIGWINCH to the
+    command, except those sent by the terminal, e.g. by Ctrl-C, which reach the
+    command already.
+
+Exit status:
+    The exit status of the command, or:
+    125                 keg failed
+    126                 The command cannot be executed
+    127                 The command was not found
+    128+N               The command was killed by signal N
 "#}
     };
 }
@@ -87,6 +203,18 @@ struct Args {
     workspace_dir: OsString,
     container: Container,
     net_nft_rules_path: Option<OsString>,
+    net_log_path: Option<OsString>,
+    status_file: Option<OsString>,
+    log_file: Option<OsString>,
+    /// Whether to print the plan as JSON, if this is a dry run.
+    dry_run: Option<bool>,
+    check_nft_rules_path: Option<OsString>,
+    name: Option<OsString>,
+    net_container: Option<OsString>,
+    /// Whether `--real-proc` was given.
+    real_proc: bool,
+    hosts: Vec<Host>,
+    dns: Vec<IpAddr>,
     container_args: Vec<OsString>,
     command: Vec<OsString>,
 }
@@ -99,31 +227,14 @@ where
         args.next(),
         msg_ret!("{} requires 2 arguments", option_name)
     );
-    let mut dest = some_or!(
+    let dest = some_or!(
         args.next(),
         msg_ret!("{} requires 2 arguments", option_name)
     );
-    true_or!(
-        !dest.as_bytes().contains(&b'/'),
-        msg_ret!("Bind destination cannot contain \"/\"")
-    );
-    true_or!(
-        !dest.as_bytes().contains(&b'\0'),
-        msg_ret!("Bind destination cannot contain the nul byte")
-    );
-    true_or!(
-        dest.as_bytes() != &b"."[..],
-        msg_ret!("Bind destination cannot be \".\"")
-    );
-    true_or!(
-        dest.as_bytes() != &b".."[..],
-        msg_ret!("Bind destination cannot be \"..\"")
-    );
-    true_or!(
-        !dest.is_empty(),
-        msg_ret!("Bind destination cannot be empty")
-    );
-    dest = OsString::from_vec([&b"/mnt/"[..], dest.as_bytes()].concat());
+    let dest = match mnt_bind_dest(&dest) {
+        Ok(dest) => dest,
+        Err(e) => msg_ret!("{}", e),
+    };
     Some(Bind { src, dest })
 }
 
@@ -144,6 +255,16 @@ fn handle_args_or_run_inner(workspace_is_home: bool) -> Option<Args> {
     let mut workspace_dir = ".".into();
     let mut container = Container::default();
     let mut net_nft_rules_path = None;
+    let mut net_log_path = None;
+    let mut status_file = None;
+    let mut log_file = None;
+    let mut dry_run = None;
+    let mut check_nft_rules_path = None;
+    let mut name = None;
+    let mut net_container = None;
+    let mut real_proc = false;
+    let mut hosts = Vec::new();
+    let mut dns = Vec::new();
     let mut container_args: Vec<OsString> = Vec::new();
     let mut command = Vec::new();
 
@@ -166,6 +287,8 @@ fn handle_args_or_run_inner(workspace_is_home: bool) -> Option<Args> {
             no_die_with_parent = true;
         } else if &arg == "--no-new-scope" {
             no_new_scope = true;
+        } else if &arg == "--no-cgroup" {
+            container.no_cgroup = true;
         } else if &arg == "-b" {
             container.base_image = Some(some_or!(args.next(), msg_ret!("-b requires an argument")));
         } else if &arg == "-r" {
@@ -184,11 +307,111 @@ fn handle_args_or_run_inner(workspace_is_home: bool) -> Option<Args> {
             container.share_net = true;
         } else if &arg == "--share-time" {
             container.share_time = true;
+        } else if &arg == "--hermetic" {
+            container.hermetic = true;
+        } else if &arg == "--private-identity" {
+            container.private_identity = true;
+        } else if &arg == "--real-proc" {
+            real_proc = true;
+        } else if &arg == "--dummy-cpus" {
+            let cpus = some_or!(args.next(), msg_ret!("--dummy-cpus requires an argument"));
+            let dummy_proc = container.dummy_proc.get_or_insert_with(DummyProc::default);
+            dummy_proc.cpus = some_or_ret!(parse_dummy_cpus(cpus));
+        } else if &arg == "--dummy-proc-file" {
+            let file = some_or_ret!(parse_dummy_proc_file(&mut args));
+            let dummy_proc = container.dummy_proc.get_or_insert_with(DummyProc::default);
+            dummy_proc.files.push(file);
+        } else if &arg == "--time-offset" {
+            let offset = some_or!(args.next(), msg_ret!("--time-offset requires an argument"));
+            let offsets = container
+                .time_offsets
+                .get_or_insert_with(TimeOffsets::default);
+            offsets.monotonic = some_or_ret!(parse_time_offset(offset));
+        } else if &arg == "--boot-time-offset" {
+            let offset = some_or!(
+                args.next(),
+                msg_ret!("--boot-time-offset requires an argument")
+            );
+            let offsets = container
+                .time_offsets
+                .get_or_insert_with(TimeOffsets::default);
+            offsets.boottime = some_or_ret!(parse_time_offset(offset));
         } else if &arg == "--net-nft-rules" {
             net_nft_rules_path = Some(some_or!(
                 args.next(),
                 msg_ret!("--net-nft-rules requires an argument")
             ));
+        } else if &arg == "--net-log" {
+            net_log_path = Some(some_or!(
+                args.next(),
+                msg_ret!("--net-log requires an argument")
+            ));
+        } else if &arg == "--status-file" {
+            status_file = Some(some_or!(
+                args.next(),
+                msg_ret!("--status-file requires an argument")
+            ));
+        } else if &arg == "--stats" {
+            container.stats = true;
+        } else if &arg == "--stats-file" {
+            container.stats_file = Some(some_or!(
+                args.next(),
+                msg_ret!("--stats-file requires an argument")
+            ));
+        } else if &arg == "--freeze-on-suspend" {
+            container.freeze_on_suspend = true;
+        } else if &arg == "--keep-running" {
+            container.keep_running = true;
+        } else if &arg == "--stop-timeout" {
+            let timeout = some_or!(args.next(), msg_ret!("--stop-timeout requires an argument"));
+            container.stop_timeout = some_or!(
+                (timeout.into_string().ok()).and_then(|x| x.parse().ok()),
+                msg_ret!("Invalid stop timeout")
+            );
+        } else if &arg == "--verbose" {
+            container.log_level = container.log_level.max(log::VERBOSE);
+        } else if &arg == "--debug" {
+            container.log_level = log::DEBUG;
+        } else if &arg == "--log-file" {
+            log_file = Some(some_or!(
+                args.next(),
+                msg_ret!("--log-file requires an argument")
+            ));
+        } else if &arg == "--dry-run" {
+            dry_run = Some(false);
+        } else if &arg == "--dry-run-json" {
+            dry_run = Some(true);
+        } else if &arg == "--check-nft-rules" {
+            check_nft_rules_path = Some(some_or!(
+                args.next(),
+                msg_ret!("--check-nft-rules requires an argument")
+            ));
+        } else if &arg == "--name" {
+            let arg = some_or!(args.next(), msg_ret!("--name requires an argument"));
+            name = Some(some_or_ret!(parse_name(arg)));
+        } else if &arg == "--net" {
+            let arg = some_or!(args.next(), msg_ret!("--net requires an argument"));
+            net_container = Some(some_or_ret!(parse_net(arg)));
+        } else if &arg == "--cidr" {
+            let cidr = some_or!(args.next(), msg_ret!("--cidr requires an argument"));
+            container.slirp.cidr = Some(some_or_ret!(parse_cidr(cidr)));
+        } else if &arg == "--mtu" {
+            let mtu = some_or!(args.next(), msg_ret!("--mtu requires an argument"));
+            container.slirp.mtu = Some(some_or_ret!(parse_mtu(mtu)));
+        } else if &arg == "--disable-ipv6" {
+            container.slirp.disable_ipv6 = true;
+        } else if &arg == "--dns-upstream" {
+            let ip = some_or!(args.next(), msg_ret!("--dns-upstream requires an argument"));
+            container.slirp.dns_upstream = Some(some_or_ret!(parse_ip(ip)));
+        } else if &arg == "--hostname" {
+            let hostname = some_or!(args.next(), msg_ret!("--hostname requires an argument"));
+            container.hostname = Some(some_or_ret!(parse_hostname(hostname)));
+        } else if &arg == "--add-host" {
+            let host = some_or!(args.next(), msg_ret!("--add-host requires an argument"));
+            hosts.push(some_or_ret!(parse_host(host)));
+        } else if &arg == "--dns" {
+            let ip = some_or!(args.next(), msg_ret!("--dns requires an argument"));
+            dns.push(some_or_ret!(parse_ip(ip)));
         } else if &arg == "-a" {
             container_args.push(some_or!(args.next(), msg_ret!("-a requires an argument")));
         } else if &arg == "--" || !arg.as_bytes().starts_with(b"-") {
@@ -213,6 +436,16 @@ fn handle_args_or_run_inner(workspace_is_home: bool) -> Option<Args> {
         workspace_dir,
         container,
         net_nft_rules_path,
+        net_log_path,
+        status_file,
+        log_file,
+        dry_run,
+        check_nft_rules_path,
+        name,
+        net_container,
+        real_proc,
+        hosts,
+        dns,
         container_args,
         command,
     })
@@ -222,25 +455,125 @@ pub fn run(workspace_is_home: bool) -> ExitCode {
     let env = env::vars_os().collect::<Vec<_>>();
     let mut args = some_or!(
         handle_args_or_run_inner(workspace_is_home),
-        return ExitCode::FAILURE
+        return KEG_FAILURE.into()
     );
+    if let Some(path) = args.check_nft_rules_path {
+        let rules = ok_or!(
+            fs::read(&path),
+            msg_and!("Failed to read nft rules"; return ExitCode::FAILURE)
+        );
+        return if check_nft_rules(
+            &rules,
+            args.container.base_image.as_deref(),
+            Path::new(&path),
+        ) {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        };
+    }
     if !args.no_die_with_parent {
         true_or!(
             set_die_with_parent(),
-            msg_and!("Failed to set die-with-parent"; return ExitCode::FAILURE)
+            msg_and!("Failed to set die-with-parent"; return KEG_FAILURE.into())
+        );
+    }
+    if !args.no_new_scope && !args.container.no_cgroup && args.dry_run.is_none() {
+        if let Some(exit_code) = new_scope() {
+            return exit_code;
+        }
+    }
+
+    let status_file = args.status_file.take();
+    let exit_code = exit_code(start(args, &env, workspace_is_home));
+    if let Some(path) = status_file {
+        write_status_file(&path, exit_code);
+    }
+    exit_code.into()
+}
+
+fn start(
+    mut args: Args,
+    env: &[(OsString, OsString)],
+    workspace_is_home: bool,
+) -> Option<ExitStatus> {
+    let dry_run = args.dry_run.is_some();
+    if let (Some(path), false) = (&args.log_file, dry_run) {
+        args.container.log_fd = Some(some_or!(open_inherited(path), return None));
+    }
+    log::init(args.container.log_level, args.container.log_fd);
+
+    let uses_cgroup = args.container.stats
+        || args.container.stats_file.is_some()
+        || args.container.freeze_on_suspend;
+    true_or!(
+        !args.container.no_cgroup || !uses_cgroup,
+        msg_and!(
+            "--stats, --stats-file and --freeze-on-suspend cannot be used with --no-cgroup";
+            return None
+        )
+    );
+    true_or!(
+        !args.container.share_time || args.container.time_offsets.is_none(),
+        msg_and!(
+            "--time-offset and --boot-time-offset cannot be used with --share-time";
+            return None
+        )
+    );
+    if args.container.hermetic {
+        true_or!(
+            !args.container.share_net && !args.container.share_time && args.net_container.is_none(),
+            msg_and!(
+                "--hermetic cannot be used with --share-net, --share-time or --net";
+                return None
+            )
         );
+        true_or!(set_hermetic(&mut args.container), return None);
+    }
+    if args.container.private_identity {
+        true_or!(set_private_identity(&mut args.container), return None);
     }
-    if !args.no_new_scope {
-        return run_in_scope();
+    let _registration = match &args.name {
+        Some(name) if !dry_run => Some(some_or!(registry::register(name), return None)),
+        _ => None,
+    };
+    if let Some(name) = &args.net_container {
+        true_or!(
+            !args.container.share_net
+                && args.net_nft_rules_path.is_none()
+                && args.net_log_path.is_none(),
+            msg_and!(
+                "--net cannot be used with --share-net, --net-nft-rules or --net-log";
+                return None
+            )
+        );
+        args.container.join_net = Some(some_or!(registry::lookup(name), return None));
     }
 
     if let Some(path) = args.net_nft_rules_path {
         let rules = ok_or!(
-            fs::read(path),
-            msg_and!("Failed to read nft rules"; return ExitCode::FAILURE)
+            fs::read(&path),
+            msg_and!("Failed to read nft rules"; return None)
+        );
+        true_or!(
+            dry_run
+                || check_nft_rules(
+                    &rules,
+                    args.container.base_image.as_deref(),
+                    Path::new(&path)
+                ),
+            return None
         );
         args.container.net_nft_rules = rules;
     }
+    if let Some(path) = &args.net_log_path {
+        // A dry run does not create the file, and only needs to know the net log is enabled.
+        args.container.net_log_fd = Some(if dry_run {
+            -1
+        } else {
+            some_or!(open_inherited(path), return None)
+        });
+    }
 
     args.container.unshare_user = Some((1000, 1000));
     args.container.options.push(Options::SetEnv(SetEnv {
@@ -289,7 +622,7 @@ pub fn run(workspace_is_home: bool) -> ExitCode {
             })),
         });
         if let Err(e) = r {
-            msg_and!("Failed to iterate filesystem: {e}"; return ExitCode::FAILURE);
+            msg_and!("Failed to iterate filesystem: {e}"; return None);
         }
     }
 
@@ -316,7 +649,19 @@ pub fn run(workspace_is_home: bool) -> ExitCode {
         dest: Path::new("/container_root_workspace").into(),
     }));
 
-    args.container.create_dummy_files = true;
+    if args.real_proc {
+        true_or!(
+            args.container.dummy_proc.is_none(),
+            msg_and!(
+                "--dummy-cpus, --dummy-proc-file and --private-identity cannot be used with --real-proc";
+                return None
+            )
+        );
+    } else {
+        args.container
+            .dummy_proc
+            .get_or_insert_with(DummyProc::default);
+    }
 
     let overlay_command = some_or!(
         overlayfs::get_command(
@@ -325,63 +670,34 @@ pub fn run(workspace_is_home: bool) -> ExitCode {
             OsStr::new("/container_overlay_upper_work"),
             OsStr::new("/container_rootfs")
         ),
-        msg_and!("Failed to get overlayfs command"; return ExitCode::FAILURE)
+        msg_and!("Failed to get overlayfs command"; return None)
     );
     args.container.command_before_unshare_user = overlay_command;
 
-    args.container.command.push("/usr/bin/podman".into());
-    args.container.command.push("run".into());
-    // cap_sys_chroot: https://github.com/containers/podman/issues/17504
-    args.container.command.push("--cap-add".into());
-    args.container.command.push("sys_chroot".into());
-    args.container.command.push("-i".into());
-    args.container
-        .command
-        .push(masked_paths::podman_arg().into());
-    args.container.command.push("--network=slirp4netns".into());
-    args.container.command.push("-t".into());
-    args.container
-        .command
-        .push("--mount=type=tmpfs,dst=/tmp".into());
-    args.container
-        .command
-        .push("--mount=type=bind,src=/container_dummy_loadavg,dst=/proc/loadavg,ro=true".into());
-    args.container
-        .command
-        .push("--mount=type=bind,src=/container_dummy_stat,dst=/proc/stat,ro=true".into());
-    args.container
-        .command
-        .push("--mount=type=bind,src=/container_dummy_uptime,dst=/proc/uptime,ro=true".into());
-    if !workspace_is_home {
-        args.container
-            .command
-            .push("--mount=type=bind,src=/container_root_workspace,dst=/root/workspace".into());
-        args.container.command.push("-w=/root/workspace".into());
-    } else {
-        args.container
-            .command
-            .push("--mount=type=bind,src=/container_root_workspace,dst=/root".into());
-        args.container.command.push("-w=/root".into());
-    }
-    args.container.command.push("--rootfs".into());
-    for arg in args.container_args {
-        args.container.command.push(arg);
-    }
-    args.container.command.push("/container_rootfs".into());
-    if args.command.is_empty() {
-        args.container.command.push("/bin/bash".into());
+    let workspace_args: [&str; 2] = if !workspace_is_home {
+        [
+            "--mount=type=bind,src=/container_root_workspace,dst=/root/workspace",
+            "-w=/root/workspace",
+        ]
     } else {
-        for arg in args.command {
-            args.container.command.push(arg);
-        }
-    }
-
-    let exit_status = some_or!(
-        start_container(&args.container, &env),
-        return ExitCode::FAILURE
+        [
+            "--mount=type=bind,src=/container_root_workspace,dst=/root",
+            "-w=/root",
+        ]
+    };
+    args.container.command = podman_command(
+        &args.container,
+        args.name.is_some() || args.net_container.is_some(),
+        &args.hosts,
+        &args.dns,
+        &workspace_args,
+        &args.container_args,
+        &args.command,
     );
-    exit_status
-        .code()
-        .map(|c| ((((c % 256) + 256) % 256) as u8).into())
-        .unwrap_or(ExitCode::FAILURE)
+
+    if let Some(json) = args.dry_run {
+        true_or!(print_plan(&args.container, env, json), return None);
+        return Some(exit_status(0));
+    }
+    start_container(&args.container, env)
 }
@@ -1,16 +1,61 @@
-use std::ffi::OsStr;
+use crate::log;
+use crate::ok_or;
+use core::ptr;
+use libc::{mount, unshare, CLONE_NEWNS, MS_BIND, MS_PRIVATE, MS_REC};
+use std::ffi::{CString, OsStr};
 use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::process::{Child, Command, Stdio};
 
-pub fn slirp<I, S>(args: I) -> io::Result<Child>
+/// Runs slirp4netns. If `resolv_conf` is set, it is bind mounted over `/etc/resolv.conf` in a
+/// private mount namespace of slirp4netns, so its DNS forwarder uses the name servers in it.
+pub fn slirp<I, S>(args: I, resolv_conf: Option<&Path>) -> io::Result<Child>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    Command::new("/usr/bin/slirp4netns")
+    let mut command = Command::new("/usr/bin/slirp4netns");
+    command
         .args(args)
         .stdin(Stdio::null())
         .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
+        .stderr(Stdio::null());
+    if let Some(resolv_conf) = resolv_conf {
+        let src = ok_or!(
+            CString::new(resolv_conf.as_os_str().as_bytes()),
+            return Err(io::ErrorKind::InvalidInput.into())
+        );
+        unsafe {
+            command.pre_exec(move || {
+                if unshare(CLONE_NEWNS) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if mount(
+                    ptr::null(),
+                    c"/".as_ptr(),
+                    ptr::null(),
+                    MS_REC | MS_PRIVATE,
+                    ptr::null(),
+                ) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                if mount(
+                    src.as_ptr(),
+                    c"/etc/resolv.conf".as_ptr(),
+                    ptr::null(),
+                    MS_BIND,
+                    ptr::null(),
+                ) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    log::command(&command);
+    command.spawn()
 }
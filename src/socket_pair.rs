@@ -13,6 +13,18 @@ pub unsafe fn set_cloexec(fd: c_int) -> bool {
     true
 }
 
+/// Makes `fd` inherited by child processes.
+pub unsafe fn clear_cloexec(fd: c_int) -> bool {
+    let flags = fcntl(fd, F_GETFD);
+    if flags < 0 {
+        return false;
+    }
+    if fcntl(fd, F_SETFD, flags & !FD_CLOEXEC) == -1 {
+        return false;
+    }
+    true
+}
+
 /// Creates a socket pair for communication with a child.
 pub fn socket_pair() -> Option<(UnixStream, c_int)> {
     let mut socks: [c_int; 2] = [0, 0];
@@ -0,0 +1,190 @@
+//! The resource usage of the container, reported by `--stats` and `--stats-file` when it exits.
+//!
+//! Everything except the wall time is read from the cgroup of the container, which must not have
+//! been removed yet. A value is `None` if its controller is not enabled in the cgroup, or the
+//! kernel does not support it, e.g. `memory.peak` requires Linux 5.19 and `pids.peak` Linux 6.13.
+
+use crate::{msg_retf, ok_or};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub wall_time: Duration,
+    pub cpu_usage_usec: Option<u64>,
+    pub cpu_user_usec: Option<u64>,
+    pub cpu_system_usec: Option<u64>,
+    pub memory_peak: Option<u64>,
+    pub io_read_bytes: Option<u64>,
+    pub io_write_bytes: Option<u64>,
+    pub pids_peak: Option<u64>,
+}
+
+/// Returns the value of `key` in a flat keyed file like `cpu.stat`.
+fn keyed_value(contents: &str, key: &str) -> Option<u64> {
+    contents.lines().find_map(|line| {
+        let (k, v) = line.split_once(' ')?;
+        if k == key {
+            v.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the bytes read and written in `io.stat`, summed over all devices.
+fn io_bytes(contents: &str) -> (u64, u64) {
+    let mut read = 0u64;
+    let mut written = 0u64;
+    for field in contents.split_whitespace() {
+        if let Some(value) = field.strip_prefix("rbytes=") {
+            read = read.saturating_add(value.parse().unwrap_or(0));
+        } else if let Some(value) = field.strip_prefix("wbytes=") {
+            written = written.saturating_add(value.parse().unwrap_or(0));
+        }
+    }
+    (read, written)
+}
+
+fn read_file(cgroup: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(cgroup.join(name)).ok()
+}
+
+/// Reads the resource usage of `cgroup`, and its descendants.
+pub fn read(cgroup: &Path, wall_time: Duration) -> Stats {
+    let cpu_stat = read_file(cgroup, "cpu.stat").unwrap_or_default();
+    let io = read_file(cgroup, "io.stat").map(|x| io_bytes(&x));
+    Stats {
+        wall_time,
+        cpu_usage_usec: keyed_value(&cpu_stat, "usage_usec"),
+        cpu_user_usec: keyed_value(&cpu_stat, "user_usec"),
+        cpu_system_usec: keyed_value(&cpu_stat, "system_usec"),
+        memory_peak: read_file(cgroup, "memory.peak").and_then(|x| x.trim().parse().ok()),
+        io_read_bytes: io.map(|x| x.0),
+        io_write_bytes: io.map(|x| x.1),
+        pids_peak: read_file(cgroup, "pids.peak").and_then(|x| x.trim().parse().ok()),
+    }
+}
+
+fn seconds(usec: u64) -> String {
+    format!("{:.3}s", usec as f64 / 1e6)
+}
+
+fn or_unavailable(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "unavailable".to_owned())
+}
+
+pub fn to_text(stats: &Stats) -> String {
+    let cpu_time =
+        stats
+            .cpu_usage_usec
+            .map(|usage| match (stats.cpu_user_usec, stats.cpu_system_usec) {
+                (Some(user), Some(system)) => format!(
+                    "{} (user {}, system {})",
+                    seconds(usage),
+                    seconds(user),
+                    seconds(system)
+                ),
+                _ => seconds(usage),
+            });
+    let io = stats
+        .io_read_bytes
+        .zip(stats.io_write_bytes)
+        .map(|(read, written)| format!("{read} bytes read, {written} bytes written"));
+
+    let mut out = String::new();
+    out.push_str(&format!(
+        "Wall time:          {:.3}s\n",
+        stats.wall_time.as_secs_f64()
+    ));
+    out.push_str(&format!(
+        "CPU time:           {}\n",
+        or_unavailable(cpu_time)
+    ));
+    out.push_str(&format!(
+        "Peak memory:        {}\n",
+        or_unavailable(stats.memory_peak.map(|x| format!("{x} bytes")))
+    ));
+    out.push_str(&format!("I/O:                {}\n", or_unavailable(io)));
+    out.push_str(&format!(
+        "Peak processes:     {}\n",
+        or_unavailable(stats.pids_peak.map(|x| x.to_string()))
+    ));
+    out
+}
+
+fn push_number(out: &mut String, key: &str, value: Option<u64>) {
+    out.push_str(&format!(",\"{key}\":"));
+    match value {
+        Some(value) => out.push_str(&value.to_string()),
+        None => out.push_str("null"),
+    }
+}
+
+pub fn to_json(stats: &Stats) -> String {
+    let mut out = format!("{{\"wall_time_usec\":{}", stats.wall_time.as_micros());
+    push_number(&mut out, "cpu_usage_usec", stats.cpu_usage_usec);
+    push_number(&mut out, "cpu_user_usec", stats.cpu_user_usec);
+    push_number(&mut out, "cpu_system_usec", stats.cpu_system_usec);
+    push_number(&mut out, "memory_peak_bytes", stats.memory_peak);
+    push_number(&mut out, "io_read_bytes", stats.io_read_bytes);
+    push_number(&mut out, "io_write_bytes", stats.io_write_bytes);
+    push_number(&mut out, "pids_peak", stats.pids_peak);
+    out.push_str("}\n");
+    out
+}
+
+pub fn write_stats_file(path: &OsStr, stats: &Stats) -> bool {
+    ok_or!(
+        fs::write(path, to_json(stats)),
+        msg_retf!("Failed to write stats file \"{}\"", path.to_string_lossy())
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cgroup_files() {
+        let cpu_stat = "usage_usec 1500\nuser_usec 1000\nsystem_usec 500\nnr_periods 0\n";
+        assert_eq!(keyed_value(cpu_stat, "usage_usec"), Some(1500));
+        assert_eq!(keyed_value(cpu_stat, "system_usec"), Some(500));
+        assert_eq!(keyed_value(cpu_stat, "usage"), None);
+
+        let io_stat = "8:0 rbytes=4096 wbytes=512 rios=1 wios=1 dbytes=0 dios=0\n\
+                       8:16 rbytes=100 wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n";
+        assert_eq!(io_bytes(io_stat), (4196, 512));
+        assert_eq!(io_bytes(""), (0, 0));
+    }
+
+    #[test]
+    fn output() {
+        let stats = Stats {
+            wall_time: Duration::from_millis(1250),
+            cpu_usage_usec: Some(2500000),
+            cpu_user_usec: Some(2000000),
+            cpu_system_usec: Some(500000),
+            memory_peak: Some(4096),
+            ..Stats::default()
+        };
+        assert_eq!(
+            to_json(&stats),
+            "{\"wall_time_usec\":1250000,\"cpu_usage_usec\":2500000,\
+             \"cpu_user_usec\":2000000,\"cpu_system_usec\":500000,\
+             \"memory_peak_bytes\":4096,\"io_read_bytes\":null,\
+             \"io_write_bytes\":null,\"pids_peak\":null}\n"
+        );
+        assert_eq!(
+            to_text(&stats),
+            "Wall time:          1.250s\n\
+             CPU time:           2.500s (user 2.000s, system 0.500s)\n\
+             Peak memory:        4096 bytes\n\
+             I/O:                unavailable\n\
+             Peak processes:     unavailable\n"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Exit codes of keg, and the status file written by `--status-file`.
+//!
+//! Like podman, keg exits with:
+//! - 125 if keg itself failed, e.g. an isolation stage could not be set up;
+//! - 126 if the command cannot be executed;
+//! - 127 if the command does not exist;
+//! - 128 + N if the command was killed by signal N;
+//! - the exit status of the command otherwise.
+
+use crate::container::Stage;
+use crate::ipc;
+use crate::json;
+use crate::{msg_retf, ok_or};
+use libc::c_int;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+pub const KEG_FAILURE: u8 = 125;
+pub const CANNOT_EXECUTE: u8 = 126;
+pub const NOT_FOUND: u8 = 127;
+
+/// Returns the exit code for `status`, or `KEG_FAILURE` if there is none.
+pub fn exit_code(status: Option<ExitStatus>) -> u8 {
+    let status = match status {
+        Some(status) => status,
+        None => return KEG_FAILURE,
+    };
+    if let Some(code) = status.code() {
+        code.rem_euclid(256) as u8
+    } else if let Some(signal) = status.signal() {
+        (128 + signal).rem_euclid(256) as u8
+    } else {
+        KEG_FAILURE
+    }
+}
+
+/// Returns the exit status a process would have when exiting with `code`.
+pub fn exit_status(code: u8) -> ExitStatus {
+    ExitStatus::from_raw(i32::from(code) << 8)
+}
+
+/// Returns the status file for a container that exited with `exit_code`, as JSON.
+///
+/// `result` is `"keg_failed"`, `"cannot_execute"`, `"not_found"`, `"signaled"` or `"exited"`.
+/// `signal` is the signal that killed the command, as reported by the exec stage, since an exit
+/// code of 128 + N can also be a plain exit. `first_error` is the first error keg reported, if
+/// any, with the stage it happened in.
+pub fn status_json(
+    exit_code: u8,
+    signal: Option<c_int>,
+    first_error: Option<(Option<Stage>, String)>,
+) -> String {
+    let signal = signal.filter(|signal| i32::from(exit_code) == 128 + signal);
+    let result = match exit_code {
+        KEG_FAILURE if first_error.is_some() => "keg_failed",
+        CANNOT_EXECUTE if first_error.is_some() => "cannot_execute",
+        NOT_FOUND if first_error.is_some() => "not_found",
+        _ if signal.is_some() => "signaled",
+        _ => "exited",
+    };
+
+    let mut out = String::new();
+    out.push_str(&format!(
+        "{{\"exit_code\":{exit_code},\"result\":\"{result}\""
+    ));
+    if let Some(signal) = signal {
+        out.push_str(&format!(",\"signal\":{signal}"));
+    }
+    if let Some((stage, error)) = first_error {
+        out.push_str(",\"stage\":");
+        match stage {
+            Some(stage) => json::push_string(&mut out, &stage.to_string()),
+            None => out.push_str("null"),
+        }
+        out.push_str(",\"error\":");
+        json::push_string(&mut out, &error);
+    }
+    out.push_str("}\n");
+    out
+}
+
+pub fn write_status_file(path: &OsStr, exit_code: u8) -> bool {
+    ok_or!(
+        fs::write(
+            path,
+            status_json(exit_code, ipc::signal(), ipc::first_error())
+        ),
+        msg_retf!("Failed to write status file \"{}\"", path.to_string_lossy())
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(exit_code(None), KEG_FAILURE);
+        assert_eq!(exit_code(Some(exit_status(0))), 0);
+        assert_eq!(exit_code(Some(exit_status(130))), 130);
+        assert_eq!(exit_code(Some(ExitStatus::from_raw(9))), 137);
+        assert_eq!(exit_code(Some(ExitStatus::from_raw(15))), 143);
+    }
+
+    #[test]
+    fn exited() {
+        assert_eq!(
+            status_json(0, None, None),
+            "{\"exit_code\":0,\"result\":\"exited\"}\n"
+        );
+        // `exit 130` is not SIGINT.
+        assert_eq!(
+            status_json(130, None, None),
+            "{\"exit_code\":130,\"result\":\"exited\"}\n"
+        );
+        // A signal that does not match the exit code is not the one that ended the container.
+        assert_eq!(
+            status_json(1, Some(2), None),
+            "{\"exit_code\":1,\"result\":\"exited\"}\n"
+        );
+        // Without an error, 125 to 127 are exit codes of the command.
+        assert_eq!(
+            status_json(KEG_FAILURE, None, None),
+            "{\"exit_code\":125,\"result\":\"exited\"}\n"
+        );
+    }
+
+    #[test]
+    fn signaled() {
+        assert_eq!(
+            status_json(130, Some(2), None),
+            "{\"exit_code\":130,\"result\":\"signaled\",\"signal\":2}\n"
+        );
+    }
+
+    #[test]
+    fn failed() {
+        let error = |stage| Some((stage, "Failed \"x\"".to_owned()));
+        assert_eq!(
+            status_json(KEG_FAILURE, None, error(Some(Stage::Isolation(2)))),
+            "{\"exit_code\":125,\"result\":\"keg_failed\",\"stage\":\"Isolation stage 2\",\"error\":\"Failed \\\"x\\\"\"}\n"
+        );
+        assert_eq!(
+            status_json(CANNOT_EXECUTE, None, error(Some(Stage::Exec))),
+            "{\"exit_code\":126,\"result\":\"cannot_execute\",\"stage\":\"Exec stage\",\"error\":\"Failed \\\"x\\\"\"}\n"
+        );
+        assert_eq!(
+            status_json(NOT_FOUND, None, error(None)),
+            "{\"exit_code\":127,\"result\":\"not_found\",\"stage\":null,\"error\":\"Failed \\\"x\\\"\"}\n"
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//! Freezing the container while keg is suspended by the terminal, e.g. by Ctrl-Z, see
+//! `--freeze-on-suspend`. Processes that handle or ignore SIGTSTP, or that are not in the
+//! foreground process group, keep running otherwise.
+
+use crate::{msg_retf, ok_or};
+use libc::{
+    c_int, close, getpid, kill, open, sigaction, sigemptyset, sighandler_t, write, O_CLOEXEC,
+    O_WRONLY, SA_RESTART, SIGSTOP, SIGTSTP,
+};
+use std::ffi::CString;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// `cgroup.freeze` of the container.
+static FREEZE_PATH: OnceLock<CString> = OnceLock::new();
+
+/// Writes `value` to `cgroup.freeze`. Only async-signal-safe functions are called.
+fn write_freeze(value: &[u8]) {
+    let path = match FREEZE_PATH.get() {
+        Some(path) => path,
+        None => return,
+    };
+    unsafe {
+        let fd = open(path.as_ptr(), O_WRONLY | O_CLOEXEC);
+        if fd >= 0 {
+            write(fd, value.as_ptr() as *const _, value.len());
+            close(fd);
+        }
+    }
+}
+
+extern "C" fn handle_suspend(_: c_int) {
+    write_freeze(b"1");
+    // Returns when keg is continued, e.g. by `fg` or `bg`.
+    unsafe { kill(getpid(), SIGSTOP) };
+    write_freeze(b"0");
+}
+
+/// Freezes `cgroup` when this process receives SIGTSTP, stops this process, and thaws `cgroup`
+/// when this process is continued.
+#[must_use]
+pub fn freeze_on_suspend(cgroup: &Path) -> bool {
+    let path = ok_or!(
+        CString::new(cgroup.join("cgroup.freeze").as_os_str().as_bytes()),
+        return false
+    );
+    drop(FREEZE_PATH.set(path));
+
+    let mut action: sigaction = unsafe { mem::zeroed() };
+    action.sa_sigaction = handle_suspend as extern "C" fn(c_int) as sighandler_t;
+    action.sa_flags = SA_RESTART;
+    unsafe { sigemptyset(&mut action.sa_mask) };
+    if unsafe { sigaction(SIGTSTP, &action, std::ptr::null_mut()) } != 0 {
+        msg_retf!("Cannot handle SIGTSTP");
+    }
+    true
+}
@@ -0,0 +1,166 @@
+//! Waiting for the next stage. Every keg process forwards SIGINT, SIGTERM, SIGHUP, SIGUSR1,
+//! SIGUSR2 and SIGWINCH to the next stage, and the exec stage to the command, so they reach the
+//! command through the whole chain. Signals sent by the kernel, e.g. by the terminal for
+//! Ctrl-C, are not forwarded, since the command receives them already.
+//!
+//! When the outermost keg process receives SIGINT, SIGTERM or SIGHUP, it kills all processes of the
+//! container if it does not exit within `--stop-timeout`, unless `--keep-running` is given.
+
+use crate::cgroup::kill_cgroup;
+use crate::{msg_ret, verbose};
+use libc::{
+    c_int, c_long, c_void, close, kill, pid_t, poll, pollfd, read, sigaddset, sigemptyset,
+    signalfd, signalfd_siginfo, sigprocmask, sigset_t, syscall, SYS_pidfd_open, EINTR, POLLIN,
+    SFD_CLOEXEC, SIGHUP, SIGINT, SIGKILL, SIGTERM, SIGUSR1, SIGUSR2, SIGWINCH, SIG_BLOCK,
+    SI_KERNEL,
+};
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::process::{Child, ExitStatus};
+use std::time::{Duration, Instant};
+
+pub const FORWARDED: [c_int; 6] = [SIGINT, SIGTERM, SIGHUP, SIGUSR1, SIGUSR2, SIGWINCH];
+const STOPPING: [c_int; 3] = [SIGINT, SIGTERM, SIGHUP];
+
+pub struct Signals {
+    fd: c_int,
+}
+
+impl Signals {
+    /// Waits for one of the signals.
+    pub fn read(&self) -> Option<signalfd_siginfo> {
+        let mut info: signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<signalfd_siginfo>();
+        if unsafe { read(self.fd, &mut info as *mut _ as *mut c_void, size) } == size as isize {
+            Some(info)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        unsafe { close(self.fd) };
+    }
+}
+
+/// How the outermost keg process stops the container.
+pub struct Stop<'a> {
+    pub timeout: Duration,
+    /// The cgroup of the container, or `None` to kill the next stage instead, whose pid namespace
+    /// holds the rest of the container.
+    pub cgroup: Option<&'a Path>,
+}
+
+/// Blocks the forwarded signals, and returns a signalfd to receive them. Children spawned with
+/// `std::process::Command` do not inherit the signal mask.
+pub fn block_signals() -> Option<Signals> {
+    block(&FORWARDED)
+}
+
+/// Blocks `signals`, and returns a signalfd to receive them.
+pub fn block(signals: &[c_int]) -> Option<Signals> {
+    let mut set: sigset_t = unsafe { mem::zeroed() };
+    unsafe { sigemptyset(&mut set) };
+    for &signal in signals {
+        unsafe { sigaddset(&mut set, signal) };
+    }
+    if unsafe { sigprocmask(SIG_BLOCK, &set, std::ptr::null_mut()) } != 0 {
+        msg_ret!("Cannot block signals: {}", io::Error::last_os_error());
+    }
+    let fd = unsafe { signalfd(-1, &set, SFD_CLOEXEC) };
+    if fd < 0 {
+        msg_ret!("Cannot create signalfd: {}", io::Error::last_os_error());
+    }
+    Some(Signals { fd })
+}
+
+/// Forwards the signal `info` to `pid`, unless it was sent by the kernel, and returns it.
+pub fn forward(info: &signalfd_siginfo, pid: pid_t) -> c_int {
+    let signal = info.ssi_signo as c_int;
+    if info.ssi_code != SI_KERNEL {
+        verbose!("Forwarding signal {} to pid {}", signal, pid);
+        unsafe { kill(pid, signal) };
+    }
+    signal
+}
+
+fn kill_container(stop: &Stop, next_pid: pid_t) {
+    verbose!(
+        "The container did not exit within {}s",
+        stop.timeout.as_secs()
+    );
+    match stop.cgroup {
+        Some(cgroup) => {
+            kill_cgroup(cgroup);
+        }
+        None => unsafe {
+            kill(next_pid, SIGKILL);
+        },
+    }
+}
+
+/// Waits for `child` to exit, forwarding the `signals` to the next stage `next_pid`. `stop` is
+/// given in the outermost keg process.
+pub fn wait(
+    child: &mut Child,
+    signals: &Signals,
+    next_pid: pid_t,
+    stop: Option<Stop>,
+) -> Option<ExitStatus> {
+    let pidfd = unsafe { syscall(SYS_pidfd_open, child.id() as c_long, 0) } as c_int;
+    if pidfd < 0 {
+        msg_ret!("Cannot open pidfd: {}", io::Error::last_os_error());
+    }
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let mut fds = [
+            pollfd {
+                fd: pidfd,
+                events: POLLIN,
+                revents: 0,
+            },
+            pollfd {
+                fd: signals.fd,
+                events: POLLIN,
+                revents: 0,
+            },
+        ];
+        let timeout = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                remaining.as_millis().min(c_int::MAX as u128) as c_int
+            }
+            None => -1,
+        };
+        let ready = unsafe { poll(fds.as_mut_ptr(), fds.len() as _, timeout) };
+        if ready < 0 {
+            if io::Error::last_os_error().raw_os_error() == Some(EINTR) {
+                continue;
+            }
+            break;
+        }
+        if let (0, Some(stop)) = (ready, &stop) {
+            kill_container(stop, next_pid);
+            deadline = None;
+            continue;
+        }
+        if fds[1].revents & POLLIN != 0 {
+            if let Some(info) = signals.read() {
+                let signal = forward(&info, next_pid);
+                if let (Some(stop), None) = (&stop, deadline) {
+                    if STOPPING.contains(&signal) {
+                        deadline = Some(Instant::now() + stop.timeout);
+                    }
+                }
+            }
+        }
+        if fds[0].revents != 0 {
+            break;
+        }
+    }
+    unsafe { close(pidfd) };
+    child.wait().ok()
+}
@@ -1,7 +1,7 @@
 #[macro_export]
 macro_rules! msg_and {
     ($fmt: expr $(, $arg: expr)*; $a: expr) => {{
-        eprintln!($fmt, $($arg,)*);
+        $crate::ipc::report(format!($fmt, $($arg,)*));
         { $a }
     }}
 }
@@ -0,0 +1,108 @@
+//! Helpers for the integration tests, which run the keg binaries built by cargo.
+//!
+//! Extra arguments for each binary are read from `$KEG_BASE_TEST_ARGS`, `$KEG_TEST_ARGS`,
+//! `$KEG_HOME_TEST_ARGS`, `$KEG_ROOTFS_TEST_ARGS` and `$KEG_USER_TEST_ARGS`, e.g. `--share-time`.
+//! Tests that need a program which is not installed are skipped, unless `$KEG_TEST_NO_SKIP` is
+//! set.
+
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+pub const BWRAP: &str = "/usr/bin/bwrap";
+pub const FUSE_OVERLAYFS: &str = "/usr/bin/fuse-overlayfs";
+pub const NFT: &str = "/usr/sbin/nft";
+pub const PODMAN: &str = "/usr/bin/podman";
+pub const SLIRP4NETNS: &str = "/usr/bin/slirp4netns";
+
+/// Returns the programs in `programs` that are not installed.
+pub fn missing<'a>(programs: &[&'a str]) -> Vec<&'a str> {
+    programs
+        .iter()
+        .copied()
+        .filter(|program| !Path::new(program).exists())
+        .collect()
+}
+
+/// Returns from the test if a program is not installed.
+macro_rules! require {
+    ($($program: expr),*) => {{
+        let missing = $crate::common::missing(&[$($program),*]);
+        if !missing.is_empty() {
+            if std::env::var_os("KEG_TEST_NO_SKIP").is_some() {
+                panic!("Not installed: {}", missing.join(", "));
+            }
+            eprintln!("Skipped: not installed: {}", missing.join(", "));
+            return;
+        }
+    }};
+}
+
+fn keg(bin: &str, var: &str) -> Command {
+    let mut command = Command::new(bin);
+    command.args(env::var(var).unwrap_or_default().split_whitespace());
+    command.stdin(Stdio::null());
+    command
+}
+
+pub fn keg_base() -> Command {
+    keg(env!("CARGO_BIN_EXE_keg-base"), "KEG_BASE_TEST_ARGS")
+}
+
+pub fn keg_workspace() -> Command {
+    keg(env!("CARGO_BIN_EXE_keg"), "KEG_TEST_ARGS")
+}
+
+pub fn keg_home() -> Command {
+    keg(env!("CARGO_BIN_EXE_keg-home"), "KEG_HOME_TEST_ARGS")
+}
+
+pub fn keg_rootfs(upper_dir: &Path) -> Command {
+    let mut command = keg(env!("CARGO_BIN_EXE_keg-rootfs"), "KEG_ROOTFS_TEST_ARGS");
+    command.arg("-u").arg(upper_dir);
+    command
+}
+
+pub fn keg_user() -> Command {
+    keg(env!("CARGO_BIN_EXE_keg-user"), "KEG_USER_TEST_ARGS")
+}
+
+/// Runs `program` without arguments in `command`.
+pub fn exec(mut command: Command, program: &str) -> Output {
+    command.args(["--", program]);
+    run(command)
+}
+
+/// Runs `script` with `/bin/sh` in `command`.
+pub fn sh(mut command: Command, script: &str) -> Output {
+    command.args(["--", "/bin/sh", "-c", script]);
+    run(command)
+}
+
+pub fn run(mut command: Command) -> Output {
+    let output = command.output().expect("Cannot run keg");
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+/// Returns the exit code of `output`, panicking if it was killed by a signal.
+pub fn code(output: &Output) -> i32 {
+    output.status.code().expect("Killed by a signal")
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// Returns an empty directory for the test `name`.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    if dir.exists() {
+        fs::remove_dir_all(&dir).expect("Cannot remove test directory");
+    }
+    fs::create_dir_all(&dir).expect("Cannot create test directory");
+    dir
+}
@@ -0,0 +1,74 @@
+#[macro_use]
+mod common;
+
+use common::*;
+use std::fs;
+
+#[test]
+fn base() {
+    require!(BWRAP);
+    assert_eq!(code(&exec(keg_base(), "/bin/true")), 0);
+    assert_eq!(code(&exec(keg_base(), "/bin/false")), 1);
+    assert_eq!(code(&sh(keg_base(), "exit 3")), 3);
+}
+
+#[test]
+fn base_command_not_found() {
+    require!(BWRAP);
+    assert_eq!(code(&exec(keg_base(), "/keg-test-not-found")), 127);
+}
+
+#[test]
+fn workspace() {
+    require!(BWRAP, FUSE_OVERLAYFS, PODMAN);
+    assert_eq!(code(&sh(keg_workspace(), "sh tests/include/true.sh")), 0);
+    assert_eq!(code(&sh(keg_workspace(), "sh tests/include/false.sh")), 1);
+}
+
+#[test]
+fn home() {
+    require!(BWRAP, FUSE_OVERLAYFS, PODMAN);
+    assert_eq!(code(&sh(keg_home(), "sh tests/include/true.sh")), 0);
+    assert_eq!(code(&sh(keg_home(), "sh tests/include/false.sh")), 1);
+}
+
+#[test]
+fn rootfs() {
+    require!(BWRAP, FUSE_OVERLAYFS, PODMAN);
+    let upper_dir = temp_dir("exit_status_rootfs");
+    assert_eq!(code(&exec(keg_rootfs(&upper_dir), "/bin/true")), 0);
+    assert_eq!(code(&exec(keg_rootfs(&upper_dir), "/bin/false")), 1);
+}
+
+#[test]
+fn user() {
+    assert_eq!(code(&sh(keg_user(), "sh tests/include/true.sh")), 0);
+    assert_eq!(code(&sh(keg_user(), "sh tests/include/false.sh")), 1);
+}
+
+#[test]
+fn status_file_tells_signals_from_exit_codes() {
+    require!(BWRAP);
+    let dir = temp_dir("status_file_tells_signals_from_exit_codes");
+    let status_file = dir.join("status.json");
+    let status = |script| {
+        let mut command = keg_base();
+        command.arg("--status-file").arg(&status_file);
+        let code = code(&sh(command, script));
+        (code, fs::read_to_string(&status_file).unwrap())
+    };
+    assert_eq!(
+        status("exit 130"),
+        (
+            130,
+            "{\"exit_code\":130,\"result\":\"exited\"}\n".to_owned()
+        )
+    );
+    assert_eq!(
+        status("kill -KILL $$"),
+        (
+            137,
+            "{\"exit_code\":137,\"result\":\"signaled\",\"signal\":9}\n".to_owned()
+        )
+    );
+}
@@ -0,0 +1,290 @@
+#[macro_use]
+mod common;
+
+use common::*;
+use std::fs;
+use std::io::{self, BufRead};
+use std::mem;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn pid_namespace_is_private() {
+    require!(BWRAP);
+    let script = format!(
+        "test ! -e /proc/{} && test $(ls /proc | grep -c '^[0-9]') -lt 10",
+        process::id()
+    );
+    assert_eq!(code(&sh(keg_base(), &script)), 0);
+}
+
+#[test]
+fn home_is_empty() {
+    require!(BWRAP);
+    let output = sh(keg_base(), "ls -A /home /home/user");
+    assert_eq!(code(&output), 0);
+    assert_eq!(stdout(&output), "/home:\nuser\n\n/home/user:\n");
+}
+
+#[test]
+fn seccomp_denies_sysinfo() {
+    require!(BWRAP);
+    let test_bin = std::env::current_exe().unwrap();
+    let mut command = keg_base();
+    command
+        .arg("--ro-bind")
+        .arg(&test_bin)
+        .arg("/keg-test")
+        .args(["--", "/keg-test", "--exact", "sysinfo_is_denied"]);
+    let output = run(command);
+    assert_eq!(code(&output), 0, "{}", stdout(&output));
+    assert!(stdout(&output).contains("1 passed"));
+}
+
+/// Run in a container by `seccomp_denies_sysinfo`.
+#[test]
+fn sysinfo_is_denied() {
+    if std::env::current_exe().unwrap() != Path::new("/keg-test") {
+        return;
+    }
+    let mut info: libc::sysinfo = unsafe { mem::zeroed() };
+    assert_eq!(unsafe { libc::sysinfo(&mut info) }, -1);
+    assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EPERM));
+}
+
+#[test]
+fn masked_proc_paths_are_empty() {
+    require!(BWRAP, FUSE_OVERLAYFS, PODMAN);
+    let script = "test ! -s /proc/cmdline && test -z \"$(ls -A /proc/bus)\"";
+    assert_eq!(code(&sh(keg_workspace(), script)), 0);
+}
+
+#[test]
+fn nft_rules_block_traffic() {
+    require!(BWRAP, NFT, SLIRP4NETNS);
+    let dir = temp_dir("nft_rules_block_traffic");
+    let rules = dir.join("rules.txt");
+    fs::write(
+        &rules,
+        "table inet keg_test {\n\
+         \tchain output {\n\
+         \t\ttype filter hook output priority 0; policy drop;\n\
+         \t}\n\
+         }\n",
+    )
+    .unwrap();
+    let connect = "timeout 10 bash -c 'exec 3<>/dev/tcp/1.1.1.1/53'";
+
+    let mut command = keg_base();
+    command.arg("--share-net");
+    if code(&sh(command, connect)) != 0 {
+        eprintln!("Skipped: no network access");
+        return;
+    }
+
+    let mut command = keg_base();
+    command
+        .arg("--share-net")
+        .arg("--net-nft-rules")
+        .arg(&rules);
+    assert_ne!(code(&sh(command, connect)), 0);
+}
+
+#[test]
+fn net_log_records_new_connections() {
+    require!(BWRAP, NFT, SLIRP4NETNS);
+    let dir = temp_dir("net_log_records_new_connections");
+    let net_log = dir.join("net.log");
+    let mut command = keg_base();
+    command.arg("--share-net").arg("--net-log").arg(&net_log);
+    // The connection is logged whether or not it is established.
+    sh(
+        command,
+        "timeout 2 bash -c 'exec 3<>/dev/tcp/192.0.2.1/443'; true",
+    );
+    let net_log = fs::read_to_string(&net_log).unwrap();
+    let (_, connections) = net_log
+        .split_once("# time\taddress\tprotocol\tport\n")
+        .unwrap();
+    assert!(connections
+        .lines()
+        .any(|line| line.ends_with("\t192.0.2.1\ttcp\t443")));
+}
+
+#[test]
+fn overlay_changes_stay_in_upper_dir() {
+    require!(BWRAP, FUSE_OVERLAYFS, PODMAN);
+    let upper_dir = temp_dir("overlay_changes_stay_in_upper_dir");
+    let file_name = format!("keg-test-{}", process::id());
+    let path = Path::new("/usr/local").join(&file_name);
+    assert!(!path.exists());
+
+    let script = format!("echo keg > {}", path.display());
+    assert_eq!(code(&sh(keg_rootfs(&upper_dir), &script)), 0);
+    assert!(!path.exists());
+    let tree_path = upper_dir.join("tree/usr/local").join(&file_name);
+    assert_eq!(fs::read_to_string(tree_path).unwrap(), "keg\n");
+
+    let output = sh(keg_rootfs(&upper_dir), &format!("cat {}", path.display()));
+    assert_eq!(code(&output), 0);
+    assert_eq!(stdout(&output), "keg\n");
+}
+
+#[test]
+fn net_container_joins_named_container() {
+    require!(BWRAP);
+    let runtime_dir = temp_dir("net_container_joins_named_container");
+    let mut command = keg_base();
+    command.env("XDG_RUNTIME_DIR", &runtime_dir);
+    command.args(["--name", "keg-test-net", "--"]);
+    command.args(["/bin/sh", "-c", "readlink /proc/self/ns/net; sleep 100"]);
+    command.stdout(process::Stdio::piped());
+    let mut child = command.spawn().unwrap();
+    let mut net = String::new();
+    io::BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut net)
+        .unwrap();
+
+    let join = || {
+        let mut command = keg_base();
+        command.env("XDG_RUNTIME_DIR", &runtime_dir);
+        command.args(["--net", "container:keg-test-net"]);
+        sh(command, "readlink /proc/self/ns/net")
+    };
+    let output = join();
+    assert_eq!(code(&output), 0);
+    assert_eq!(stdout(&output), net);
+
+    // Joining fails cleanly once the container has exited.
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    child.wait().unwrap();
+    let output = join();
+    assert_eq!(code(&output), 125);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No container named keg-test-net"));
+}
+
+#[test]
+fn sigterm_kills_container() {
+    require!(BWRAP);
+    let mut command = keg_base();
+    // The shell ignores SIGTERM, so only keg can stop it.
+    command.args([
+        "--stop-timeout",
+        "1",
+        "--",
+        "/bin/sh",
+        "-c",
+        "trap '' TERM; sleep 100",
+    ]);
+    let mut child = command.spawn().unwrap();
+    thread::sleep(Duration::from_secs(1));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    for _ in 0..100 {
+        if child.try_wait().unwrap().is_some() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    drop(child.kill());
+    panic!("The container is still running");
+}
+
+#[test]
+fn signals_are_forwarded() {
+    require!(BWRAP);
+    let mut command = keg_base();
+    command.args([
+        "--",
+        "/bin/sh",
+        "-c",
+        "trap 'exit 7' USR1; sleep 100 & wait",
+    ]);
+    let mut child = command.spawn().unwrap();
+    thread::sleep(Duration::from_secs(1));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGUSR1) };
+    for _ in 0..100 {
+        if let Some(status) = child.try_wait().unwrap() {
+            assert_eq!(status.code(), Some(7));
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    drop(child.kill());
+    panic!("The signal was not forwarded");
+}
+
+#[test]
+fn init_reaps_orphans() {
+    require!(BWRAP);
+    // The inner shell exits before its `sleep`, which becomes a child of the init process, i.e.
+    // of the parent of the command. Without `--init`, it would be reaped by PID 1 of bwrap.
+    let script = "sh -c 'sleep 0.2 & exit 0'; sleep 1; \
+        for pid in $(cat /proc/$PPID/task/*/children); do test $pid = $$ || exit 1; done";
+    let mut command = keg_base();
+    command.args(["--init", "--verbose"]);
+    let output = sh(command, script);
+    assert_eq!(code(&output), 0);
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("Exec stage: Reaped pid "), "{log}");
+
+    let mut command = keg_base();
+    command.arg("--verbose");
+    let output = sh(command, script);
+    assert_eq!(code(&output), 0);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Reaped pid"));
+}
+
+#[test]
+fn hermetic_is_reproducible() {
+    require!(BWRAP);
+    // The wall clock cannot be changed, so `date` prints `$SOURCE_DATE_EPOCH`.
+    let script = "date -d \"@$SOURCE_DATE_EPOCH\"; date +%Z; cat /etc/timezone; \
+        touch -d \"@$SOURCE_DATE_EPOCH\" /tmp/file; ls -l --time-style=full-iso /tmp; \
+        uname -n; cut -d . -f 1 /proc/uptime; env | sort";
+    let run = || {
+        let mut command = keg_base();
+        command.arg("--hermetic");
+        let output = sh(command, script);
+        assert_eq!(code(&output), 0);
+        stdout(&output)
+    };
+    let first = run();
+    assert!(first.contains("1980"));
+    assert_eq!(first, run());
+}
+
+#[test]
+fn boot_time_offset_moves_uptime() {
+    require!(BWRAP);
+    let mut command = keg_base();
+    command.args(["--boot-time-offset", "30d"]);
+    let script = "awk '{ exit !($1 >= 2592000) }' /proc/uptime";
+    assert_eq!(code(&sh(command, script)), 0);
+}
+
+#[test]
+fn dummy_proc_advertises_cpus() {
+    require!(BWRAP);
+    let mut command = keg_base();
+    command.args(["--dummy-cpus", "4"]);
+    let script = "test $(grep -c '^cpu[0-9]' /proc/stat) = 4 && test \"$(cat /proc/uptime)\" = '100.00 100.00'";
+    assert_eq!(code(&sh(command, script)), 0);
+}
+
+#[test]
+fn private_identity_hides_machine_id() {
+    require!(BWRAP);
+    let host_id = fs::read_to_string("/etc/machine-id").unwrap_or_default();
+    let mut command = keg_base();
+    command.arg("--private-identity");
+    let script = format!(
+        "test \"$(hostname)\" = container && grep -q 'Virtual CPU' /proc/cpuinfo && \
+         test ! -s /proc/version && \
+         for id in /etc/machine-id /var/lib/dbus/machine-id; do \
+         test ! -e $id || test \"$(cat $id)\" != '{}' || exit 1; done",
+        host_id.trim()
+    );
+    assert_eq!(code(&sh(command, &script)), 0);
+}
@@ -0,0 +1,36 @@
+#[macro_use]
+mod common;
+
+use common::*;
+use std::process::{self, Command};
+use std::thread;
+use std::time::Duration;
+
+fn control(bin: &str, name: &str) -> i32 {
+    let mut command = Command::new(bin);
+    command.arg(name);
+    code(&run(command))
+}
+
+#[test]
+fn pause_and_resume() {
+    require!(BWRAP);
+    let name = format!("keg-test-pause-{}", process::id());
+    let mut command = keg_base();
+    command.args(["--name", &name, "--", "/bin/sh", "-c", "sleep 2"]);
+    let mut child = command.spawn().unwrap();
+
+    // Wait for the container to be registered.
+    let pause = env!("CARGO_BIN_EXE_keg-pause");
+    let mut paused = false;
+    for _ in 0..50 {
+        if control(pause, &name) == 0 {
+            paused = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(paused);
+    assert_eq!(control(env!("CARGO_BIN_EXE_keg-resume"), &name), 0);
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+}
@@ -0,0 +1,18 @@
+#[macro_use]
+mod common;
+
+use common::*;
+use std::fs;
+
+#[test]
+fn stats_file_is_written() {
+    require!(BWRAP);
+    let dir = temp_dir("stats_file_is_written");
+    let stats_file = dir.join("stats.json");
+    let mut command = keg_base();
+    command.arg("--stats-file").arg(&stats_file);
+    assert_eq!(code(&sh(command, "exit 3")), 3);
+    let stats = fs::read_to_string(stats_file).unwrap();
+    assert!(stats.starts_with("{\"wall_time_usec\":"), "{stats}");
+    assert!(stats.contains(",\"cpu_usage_usec\":"), "{stats}");
+}